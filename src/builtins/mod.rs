@@ -1,17 +1,33 @@
 use std::collections::HashMap;
 use std::env;
 use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
 
-use crate::utils::{find_executable, write_line};
+use crate::shell::Shell;
+use crate::utils::{find_all_executables, find_executable, is_valid_identifier, write_line};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BuiltinFlow {
-    Continue,
+    Continue(i32),
     Exit(i32),
+    Return(i32),
+}
+
+impl BuiltinFlow {
+    pub fn status(self) -> i32 {
+        match self {
+            BuiltinFlow::Continue(status)
+            | BuiltinFlow::Exit(status)
+            | BuiltinFlow::Return(status) => status,
+        }
+    }
 }
 
 pub type BuiltinFn =
-    fn(&Builtins, &[String], &mut dyn Write, &mut dyn Write) -> io::Result<BuiltinFlow>;
+    fn(&mut Shell, &[String], &mut dyn Write, &mut dyn Write) -> io::Result<BuiltinFlow>;
+
+const RECENT_DIRS_LIMIT: usize = 10;
 
 pub struct Builtins {
     registry: HashMap<&'static str, BuiltinFn>,
@@ -25,6 +41,17 @@ impl Builtins {
         registry.insert("type", Builtins::builtin_type);
         registry.insert("pwd", Builtins::builtin_pwd);
         registry.insert("cd", Builtins::builtin_cd);
+        registry.insert("which", Builtins::builtin_which);
+        registry.insert("export", Builtins::builtin_export);
+        registry.insert("unset", Builtins::builtin_unset);
+        registry.insert("history", Builtins::builtin_history);
+        registry.insert("jobs", Builtins::builtin_jobs);
+        registry.insert("set", Builtins::builtin_set);
+        registry.insert("source", Builtins::builtin_source);
+        registry.insert(".", Builtins::builtin_source);
+        registry.insert("return", Builtins::builtin_return);
+        registry.insert("alias", Builtins::builtin_alias);
+        registry.insert("unalias", Builtins::builtin_unalias);
         Builtins { registry }
     }
 
@@ -32,25 +59,29 @@ impl Builtins {
         self.registry.get(name)
     }
 
+    pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.registry.keys().copied()
+    }
+
     pub fn is_builtin(&self, name: &str) -> bool {
         self.registry.contains_key(name)
     }
 
     fn builtin_exit(
-        &self,
+        _shell: &mut Shell,
         parts: &[String],
         _stdout_writer: &mut dyn Write,
         stderr_writer: &mut dyn Write,
     ) -> io::Result<BuiltinFlow> {
         let status_code = if parts.len() > 1 {
-            match parts[1].parse::<i32>() {
-                Ok(code) => code,
+            match parts[1].parse::<i64>() {
+                Ok(code) => (code & 0xFF) as i32,
                 Err(_) => {
                     write_line(
                         stderr_writer,
                         &format!("exit: {}: numeric argument required", parts[1]),
                     )?;
-                    return Ok(BuiltinFlow::Continue);
+                    return Ok(BuiltinFlow::Continue(1));
                 }
             }
         } else {
@@ -61,44 +92,108 @@ impl Builtins {
     }
 
     fn builtin_echo(
-        &self,
+        _shell: &mut Shell,
         parts: &[String],
         stdout_writer: &mut dyn Write,
         _stderr_writer: &mut dyn Write,
     ) -> io::Result<BuiltinFlow> {
-        let message = parts[1..].join(" ");
-        write_line(stdout_writer, &message)?;
-        Ok(BuiltinFlow::Continue)
+        let mut print_newline = true;
+        let mut interpret_escapes = false;
+        let mut args = &parts[1..];
+        while let Some(flags) = args.first().and_then(|arg| echo_flags(arg)) {
+            for flag in flags.chars() {
+                match flag {
+                    'n' => print_newline = false,
+                    'e' => interpret_escapes = true,
+                    _ => interpret_escapes = false,
+                }
+            }
+            args = &args[1..];
+        }
+
+        let mut message = args.join(" ");
+        if interpret_escapes {
+            let (expanded, stop_output) = expand_echo_escapes(&message);
+            message = expanded;
+            if stop_output {
+                print_newline = false;
+            }
+        }
+        stdout_writer.write_all(message.as_bytes())?;
+        if print_newline {
+            stdout_writer.write_all(b"\n")?;
+        }
+        Ok(BuiltinFlow::Continue(0))
     }
 
     fn builtin_type(
-        &self,
+        shell: &mut Shell,
         parts: &[String],
         stdout_writer: &mut dyn Write,
         stderr_writer: &mut dyn Write,
     ) -> io::Result<BuiltinFlow> {
-        if parts.len() != 2 {
-            write_line(stderr_writer, "type only accepts 2 arguments")?;
-            return Ok(BuiltinFlow::Continue);
-        }
+        let (show_all, names) = match parts.get(1).map(String::as_str) {
+            Some("-a") => (true, &parts[2..]),
+            _ => (false, &parts[1..]),
+        };
+        let mut status = 0;
+        for target in names {
+            let is_builtin = shell.builtins.is_builtin(target.as_str());
+            if is_builtin {
+                write_line(stdout_writer, &format!("{target} is a shell builtin"))?;
+                if !show_all {
+                    continue;
+                }
+            }
 
-        let target = &parts[1];
-        if self.is_builtin(target.as_str()) {
-            write_line(stdout_writer, &format!("{target} is a shell builtin"))?;
-            return Ok(BuiltinFlow::Continue);
+            let paths = if show_all {
+                find_all_executables(target)
+            } else {
+                find_executable(target).into_iter().collect()
+            };
+            for path in &paths {
+                write_line(stdout_writer, &format!("{target} is {}", path.display()))?;
+            }
+            if paths.is_empty() && !is_builtin {
+                write_line(stderr_writer, &format!("{target}: not found"))?;
+                status = 1;
+            }
         }
 
-        if let Some(path) = find_executable(target) {
-            write_line(stdout_writer, &format!("{target} is {}", path.display()))?;
-        } else {
-            write_line(stderr_writer, &format!("{target}: not found"))?;
+        Ok(BuiltinFlow::Continue(status))
+    }
+
+    fn builtin_which(
+        _shell: &mut Shell,
+        parts: &[String],
+        stdout_writer: &mut dyn Write,
+        _stderr_writer: &mut dyn Write,
+    ) -> io::Result<BuiltinFlow> {
+        let (show_all, names) = match parts.get(1).map(String::as_str) {
+            Some("-a") => (true, &parts[2..]),
+            _ => (false, &parts[1..]),
+        };
+
+        let mut status = 0;
+        for name in names {
+            let paths = if show_all {
+                find_all_executables(name)
+            } else {
+                find_executable(name).into_iter().collect()
+            };
+            if paths.is_empty() {
+                status = 1;
+            }
+            for path in paths {
+                write_line(stdout_writer, &path.to_string_lossy())?;
+            }
         }
 
-        Ok(BuiltinFlow::Continue)
+        Ok(BuiltinFlow::Continue(status))
     }
 
     fn builtin_pwd(
-        &self,
+        _shell: &mut Shell,
         _parts: &[String],
         stdout_writer: &mut dyn Write,
         stderr_writer: &mut dyn Write,
@@ -109,49 +204,553 @@ impl Builtins {
             }
             Err(_) => {
                 write_line(stderr_writer, "Can't find current directory")?;
+                return Ok(BuiltinFlow::Continue(1));
             }
         }
 
-        Ok(BuiltinFlow::Continue)
+        Ok(BuiltinFlow::Continue(0))
     }
 
     fn builtin_cd(
-        &self,
+        shell: &mut Shell,
         parts: &[String],
-        _stdout_writer: &mut dyn Write,
+        stdout_writer: &mut dyn Write,
         stderr_writer: &mut dyn Write,
     ) -> io::Result<BuiltinFlow> {
-        if parts.len() != 2 {
+        let operands = match parts.get(1).map(String::as_str) {
+            // `cd --` and `cd -- N` use the recent list; otherwise `--` just ends the options.
+            Some("--") => {
+                let rest = &parts[2..];
+                if rest
+                    .first()
+                    .map_or(true, |arg| arg.parse::<usize>().is_ok())
+                {
+                    return Builtins::cd_recent(shell, rest, stdout_writer, stderr_writer);
+                }
+                rest
+            }
+            _ => &parts[1..],
+        };
+        if operands.is_empty() {
+            return Builtins::cd_home(shell, env::var("HOME").ok(), stderr_writer);
+        }
+        if operands.len() != 1 {
             write_line(stderr_writer, "cd only accepts 1 argument")?;
-            return Ok(BuiltinFlow::Continue);
+            return Ok(BuiltinFlow::Continue(1));
+        }
+
+        if operands[0] == "-" {
+            let Ok(old_dir) = env::var("OLDPWD") else {
+                write_line(stderr_writer, "cd: OLDPWD not set")?;
+                return Ok(BuiltinFlow::Continue(1));
+            };
+            if !Builtins::change_dir(shell, &old_dir) {
+                write_line(
+                    stderr_writer,
+                    &format!("{}: No such file or directory", old_dir),
+                )?;
+                return Ok(BuiltinFlow::Continue(1));
+            }
+            write_line(stdout_writer, &old_dir)?;
+            return Ok(BuiltinFlow::Continue(0));
+        }
+
+        if !Builtins::change_dir(shell, &operands[0]) {
+            write_line(
+                stderr_writer,
+                &format!("{}: No such file or directory", operands[0]),
+            )?;
+            return Ok(BuiltinFlow::Continue(1));
+        }
+
+        Ok(BuiltinFlow::Continue(0))
+    }
+
+    fn builtin_export(
+        _shell: &mut Shell,
+        parts: &[String],
+        stdout_writer: &mut dyn Write,
+        stderr_writer: &mut dyn Write,
+    ) -> io::Result<BuiltinFlow> {
+        if parts.len() == 1 {
+            let mut vars: Vec<(String, String)> = env::vars().collect();
+            vars.sort();
+            for (name, value) in vars {
+                let line = format!("declare -x {}=\"{}\"", name, double_quote_escape(&value));
+                write_line(stdout_writer, &line)?;
+            }
+            return Ok(BuiltinFlow::Continue(0));
+        }
+
+        let mut status = 0;
+        for arg in &parts[1..] {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            if !is_valid_identifier(name) {
+                write_line(
+                    stderr_writer,
+                    &format!("export: {}: not a valid identifier", name),
+                )?;
+                status = 1;
+                continue;
+            }
+            if let Some(value) = value {
+                env::set_var(name, value);
+            }
+        }
+
+        Ok(BuiltinFlow::Continue(status))
+    }
+
+    fn builtin_unset(
+        _shell: &mut Shell,
+        parts: &[String],
+        _stdout_writer: &mut dyn Write,
+        stderr_writer: &mut dyn Write,
+    ) -> io::Result<BuiltinFlow> {
+        let mut status = 0;
+        for name in &parts[1..] {
+            if is_valid_identifier(name) {
+                env::remove_var(name);
+            } else {
+                write_line(
+                    stderr_writer,
+                    &format!("unset: {}: not a valid identifier", name),
+                )?;
+                status = 1;
+            }
+        }
+
+        Ok(BuiltinFlow::Continue(status))
+    }
+
+    fn builtin_history(
+        shell: &mut Shell,
+        parts: &[String],
+        stdout_writer: &mut dyn Write,
+        stderr_writer: &mut dyn Write,
+    ) -> io::Result<BuiltinFlow> {
+        let mut skip = 0;
+        match parts.get(1).map(String::as_str) {
+            None => {}
+            Some("-c") => {
+                shell.clear_history();
+                return Ok(BuiltinFlow::Continue(0));
+            }
+            Some(count) => match count.parse::<usize>() {
+                Ok(count) => skip = shell.history.len().saturating_sub(count),
+                Err(_) => {
+                    write_line(
+                        stderr_writer,
+                        &format!("history: {}: numeric argument required", count),
+                    )?;
+                    return Ok(BuiltinFlow::Continue(1));
+                }
+            },
+        }
+
+        for (index, entry) in shell.history.iter().enumerate().skip(skip) {
+            write_line(stdout_writer, &format!("{:>5}  {}", index + 1, entry))?;
+        }
+
+        Ok(BuiltinFlow::Continue(0))
+    }
+
+    fn builtin_jobs(
+        shell: &mut Shell,
+        _parts: &[String],
+        stdout_writer: &mut dyn Write,
+        _stderr_writer: &mut dyn Write,
+    ) -> io::Result<BuiltinFlow> {
+        for line in shell.jobs.list() {
+            write_line(stdout_writer, &line)?;
+        }
+
+        Ok(BuiltinFlow::Continue(0))
+    }
+
+    fn builtin_set(
+        shell: &mut Shell,
+        parts: &[String],
+        stdout_writer: &mut dyn Write,
+        stderr_writer: &mut dyn Write,
+    ) -> io::Result<BuiltinFlow> {
+        let enable = match parts.get(1).map(String::as_str) {
+            Some("-o") => true,
+            Some("+o") => false,
+            _ => {
+                write_line(stderr_writer, "set: usage: set [-o|+o] pipefail")?;
+                return Ok(BuiltinFlow::Continue(2));
+            }
+        };
+        match parts.get(2).map(String::as_str) {
+            Some("pipefail") => shell.pipefail = enable,
+            Some(name) => {
+                write_line(
+                    stderr_writer,
+                    &format!("set: {}: invalid option name", name),
+                )?;
+                return Ok(BuiltinFlow::Continue(2));
+            }
+            None => {
+                let state = if shell.pipefail { "on" } else { "off" };
+                write_line(stdout_writer, &format!("pipefail\t{}", state))?;
+            }
+        }
+
+        Ok(BuiltinFlow::Continue(0))
+    }
+
+    fn builtin_alias(
+        shell: &mut Shell,
+        parts: &[String],
+        stdout_writer: &mut dyn Write,
+        stderr_writer: &mut dyn Write,
+    ) -> io::Result<BuiltinFlow> {
+        if parts.len() == 1 {
+            let mut aliases: Vec<_> = shell.aliases.iter().collect();
+            aliases.sort();
+            for (name, value) in aliases {
+                write_line(stdout_writer, &alias_definition(name, value))?;
+            }
+            return Ok(BuiltinFlow::Continue(0));
         }
 
-        let mut new_dir = parts[1].clone();
-        if parts[1].starts_with('~') {
-            if let Ok(home_dir) = env::var("HOME") {
-                let remainder = parts[1].trim_start_matches('~');
-                if remainder.is_empty() {
-                    new_dir = home_dir;
-                } else {
-                    new_dir = format!("{}/{}", home_dir, remainder.trim_start_matches('/'));
+        let mut status = 0;
+        for arg in &parts[1..] {
+            match arg.split_once('=') {
+                Some((name, value)) if !name.is_empty() => {
+                    shell.aliases.insert(name.to_string(), value.to_string());
                 }
+                _ => match shell.aliases.get(arg) {
+                    Some(value) => write_line(stdout_writer, &alias_definition(arg, value))?,
+                    None => {
+                        write_line(stderr_writer, &format!("alias: {}: not found", arg))?;
+                        status = 1;
+                    }
+                },
+            }
+        }
+
+        Ok(BuiltinFlow::Continue(status))
+    }
+
+    fn builtin_unalias(
+        shell: &mut Shell,
+        parts: &[String],
+        _stdout_writer: &mut dyn Write,
+        stderr_writer: &mut dyn Write,
+    ) -> io::Result<BuiltinFlow> {
+        if parts.len() == 1 {
+            write_line(
+                stderr_writer,
+                "unalias: usage: unalias [-a] name [name ...]",
+            )?;
+            return Ok(BuiltinFlow::Continue(2));
+        }
+        if parts[1] == "-a" {
+            shell.aliases.clear();
+            return Ok(BuiltinFlow::Continue(0));
+        }
+
+        let mut status = 0;
+        for name in &parts[1..] {
+            if shell.aliases.remove(name).is_none() {
+                write_line(stderr_writer, &format!("unalias: {}: not found", name))?;
+                status = 1;
+            }
+        }
+
+        Ok(BuiltinFlow::Continue(status))
+    }
+
+    fn builtin_source(
+        shell: &mut Shell,
+        parts: &[String],
+        _stdout_writer: &mut dyn Write,
+        stderr_writer: &mut dyn Write,
+    ) -> io::Result<BuiltinFlow> {
+        let Some(path) = parts.get(1) else {
+            write_line(
+                stderr_writer,
+                &format!("{}: filename argument required", parts[0]),
+            )?;
+            return Ok(BuiltinFlow::Continue(2));
+        };
+
+        shell.source_depth += 1;
+        let result = shell.source_file(path);
+        shell.source_depth -= 1;
+        match result {
+            Ok(result) if result.exit_requested => Ok(BuiltinFlow::Exit(result.status)),
+            Ok(result) => Ok(BuiltinFlow::Continue(result.status)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                write_line(
+                    stderr_writer,
+                    &format!("{}: {}: No such file or directory", parts[0], path),
+                )?;
+                Ok(BuiltinFlow::Continue(1))
             }
+            Err(err) => {
+                write_line(stderr_writer, &format!("{}: {}: {}", parts[0], path, err))?;
+                Ok(BuiltinFlow::Continue(1))
+            }
+        }
+    }
+
+    fn builtin_return(
+        shell: &mut Shell,
+        parts: &[String],
+        _stdout_writer: &mut dyn Write,
+        stderr_writer: &mut dyn Write,
+    ) -> io::Result<BuiltinFlow> {
+        if shell.source_depth == 0 {
+            write_line(
+                stderr_writer,
+                "return: can only `return' from a function or sourced script",
+            )?;
+            return Ok(BuiltinFlow::Continue(1));
         }
 
-        if env::set_current_dir(&new_dir).is_err() {
+        let status = match parts.get(1) {
+            Some(arg) => match arg.parse::<i64>() {
+                Ok(code) => (code & 0xFF) as i32,
+                Err(_) => {
+                    write_line(
+                        stderr_writer,
+                        &format!("return: {}: numeric argument required", arg),
+                    )?;
+                    return Ok(BuiltinFlow::Continue(1));
+                }
+            },
+            None => shell.last_status,
+        };
+        Ok(BuiltinFlow::Return(status))
+    }
+
+    fn cd_home(
+        shell: &mut Shell,
+        home: Option<String>,
+        stderr_writer: &mut dyn Write,
+    ) -> io::Result<BuiltinFlow> {
+        let Some(home) = home else {
+            write_line(stderr_writer, "cd: HOME not set")?;
+            return Ok(BuiltinFlow::Continue(1));
+        };
+        if !Builtins::change_dir(shell, &home) {
             write_line(
                 stderr_writer,
-                &format!("{}: No such file or directory", parts[1]),
+                &format!("{}: No such file or directory", home),
             )?;
+            return Ok(BuiltinFlow::Continue(1));
+        }
+        Ok(BuiltinFlow::Continue(0))
+    }
+
+    fn cd_recent(
+        shell: &mut Shell,
+        args: &[String],
+        stdout_writer: &mut dyn Write,
+        stderr_writer: &mut dyn Write,
+    ) -> io::Result<BuiltinFlow> {
+        let Some(index) = args.first() else {
+            for (index, dir) in shell.recent_dirs.iter().enumerate() {
+                write_line(
+                    stdout_writer,
+                    &format!("{:>2}  {}", index + 1, dir.display()),
+                )?;
+            }
+            return Ok(BuiltinFlow::Continue(0));
+        };
+
+        let target = index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| index.checked_sub(1))
+            .and_then(|index| shell.recent_dirs.get(index))
+            .cloned();
+        match target {
+            Some(dir) => {
+                if !Builtins::change_dir(shell, &dir) {
+                    write_line(
+                        stderr_writer,
+                        &format!("{}: No such file or directory", dir.display()),
+                    )?;
+                    return Ok(BuiltinFlow::Continue(1));
+                }
+            }
+            None => {
+                write_line(
+                    stderr_writer,
+                    &format!("cd: {}: no such recent directory", index),
+                )?;
+                return Ok(BuiltinFlow::Continue(1));
+            }
+        }
+
+        Ok(BuiltinFlow::Continue(0))
+    }
+
+    fn change_dir<P: AsRef<Path>>(shell: &mut Shell, new_dir: P) -> bool {
+        let new_dir = new_dir.as_ref();
+        let pwd = logical_pwd();
+        let previous_dir = pwd.clone().or_else(|| env::current_dir().ok());
+        // Like bash's default -L mode, `..` steps back out of the path in $PWD, not the real one.
+        let logical = pwd
+            .map(|pwd| logical_join(&pwd, new_dir))
+            .filter(|target| env::set_current_dir(target).is_ok());
+        if logical.is_none() && env::set_current_dir(new_dir).is_err() {
+            return false;
+        }
+        if let Some(current_dir) = logical.or_else(|| env::current_dir().ok()) {
+            env::set_var("PWD", current_dir);
+        }
+        if let Some(previous_dir) = previous_dir {
+            env::set_var("OLDPWD", &previous_dir);
+            Builtins::remember_dir(shell, previous_dir);
+        }
+        true
+    }
+
+    fn remember_dir(shell: &mut Shell, dir: PathBuf) {
+        shell.recent_dirs.retain(|recent| recent != &dir);
+        shell.recent_dirs.insert(0, dir);
+        shell.recent_dirs.truncate(RECENT_DIRS_LIMIT);
+    }
+}
+
+// $PWD only counts while it still names the directory we are actually in.
+fn logical_pwd() -> Option<PathBuf> {
+    let pwd = PathBuf::from(env::var_os("PWD")?);
+    let named = pwd.metadata().ok()?;
+    let actual = Path::new(".").metadata().ok()?;
+    (pwd.is_absolute() && named.dev() == actual.dev() && named.ino() == actual.ino()).then_some(pwd)
+}
+
+fn logical_join(base: &Path, path: &Path) -> PathBuf {
+    let mut joined = base.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                joined.pop();
+            }
+            Component::CurDir => {}
+            other => joined.push(other),
+        }
+    }
+    joined
+}
+
+fn alias_definition(name: &str, value: &str) -> String {
+    format!("alias {}='{}'", name, value.replace('\'', r"'\''"))
+}
+
+// Like bash's listing, the value can be read back inside double quotes.
+fn double_quote_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        if matches!(ch, '"' | '$' | '`' | '\\') {
+            escaped.push('\\');
         }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+fn echo_flags(arg: &str) -> Option<&str> {
+    let flags = arg.strip_prefix('-')?;
+    if !flags.is_empty() && flags.chars().all(|c| matches!(c, 'n' | 'e' | 'E')) {
+        Some(flags)
+    } else {
+        None
+    }
+}
 
-        Ok(BuiltinFlow::Continue)
+fn expand_echo_escapes(input: &str) -> (String, bool) {
+    let mut output = String::new();
+    let mut chars = input.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            output.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => output.push('\\'),
+            Some('a') => output.push('\x07'),
+            Some('b') => output.push('\x08'),
+            Some('c') => return (output, true),
+            Some('e') => output.push('\x1b'),
+            Some('f') => output.push('\x0c'),
+            Some('n') => output.push('\n'),
+            Some('r') => output.push('\r'),
+            Some('t') => output.push('\t'),
+            Some('v') => output.push('\x0b'),
+            Some('0') => {
+                let mut value = 0;
+                for _ in 0..3 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(digit) => {
+                            value = value * 8 + digit;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                output.push(char::from_u32(value).unwrap_or('\0'));
+            }
+            Some('x') if chars.peek().is_some_and(|c| c.is_ascii_hexdigit()) => {
+                let mut value = 0;
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(16)) {
+                        Some(digit) => {
+                            value = value * 16 + digit;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                output.push(char::from_u32(value).unwrap_or('\0'));
+            }
+            Some(other) => {
+                output.push('\\');
+                output.push(other);
+            }
+            None => output.push('\\'),
+        }
     }
+    (output, false)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::{restore_var, TempDir, CWD_LOCK};
+    use std::ffi::OsString;
+
+    // cd also rewrites PWD and OLDPWD, so tests put those back along with the directory.
+    struct SavedDir {
+        dir: PathBuf,
+        pwd: Option<OsString>,
+        oldpwd: Option<OsString>,
+    }
+
+    impl SavedDir {
+        fn new() -> Self {
+            SavedDir {
+                dir: env::current_dir().unwrap(),
+                pwd: env::var_os("PWD"),
+                oldpwd: env::var_os("OLDPWD"),
+            }
+        }
+
+        fn restore(self) {
+            env::set_current_dir(&self.dir).unwrap();
+            restore_var("PWD", self.pwd);
+            restore_var("OLDPWD", self.oldpwd);
+        }
+    }
 
     fn parts(words: &[&str]) -> Vec<String> {
         words.iter().map(|word| word.to_string()).collect()
@@ -159,38 +758,38 @@ mod tests {
 
     #[test]
     fn echo_writes_joined_message() {
-        let builtins = Builtins::new();
+        let mut shell = Shell::new();
         let mut stdout = Vec::new();
         let mut stderr = Vec::new();
 
         let flow = Builtins::builtin_echo(
-            &builtins,
+            &mut shell,
             &parts(&["echo", "hello", "world"]),
             &mut stdout,
             &mut stderr,
         )
         .unwrap();
 
-        assert_eq!(BuiltinFlow::Continue, flow);
+        assert_eq!(BuiltinFlow::Continue(0), flow);
         assert_eq!("hello world\n", String::from_utf8(stdout).unwrap());
         assert!(stderr.is_empty());
     }
 
     #[test]
     fn exit_with_invalid_argument_reports_error() {
-        let builtins = Builtins::new();
+        let mut shell = Shell::new();
         let mut stdout = Vec::new();
         let mut stderr = Vec::new();
 
         let flow = Builtins::builtin_exit(
-            &builtins,
+            &mut shell,
             &parts(&["exit", "oops"]),
             &mut stdout,
             &mut stderr,
         )
         .unwrap();
 
-        assert_eq!(BuiltinFlow::Continue, flow);
+        assert_eq!(BuiltinFlow::Continue(1), flow);
         assert_eq!(
             "exit: oops: numeric argument required\n",
             String::from_utf8(stderr).unwrap()
@@ -200,23 +799,504 @@ mod tests {
 
     #[test]
     fn type_reports_builtin() {
-        let builtins = Builtins::new();
+        let mut shell = Shell::new();
         let mut stdout = Vec::new();
         let mut stderr = Vec::new();
 
         let flow = Builtins::builtin_type(
-            &builtins,
+            &mut shell,
             &parts(&["type", "echo"]),
             &mut stdout,
             &mut stderr,
         )
         .unwrap();
 
-        assert_eq!(BuiltinFlow::Continue, flow);
+        assert_eq!(BuiltinFlow::Continue(0), flow);
         assert_eq!(
             "echo is a shell builtin\n",
             String::from_utf8(stdout).unwrap()
         );
         assert!(stderr.is_empty());
     }
+
+    #[test]
+    fn type_reports_each_name_and_fails_if_any_is_missing() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+
+        let flow = Builtins::builtin_type(
+            &mut shell,
+            &parts(&["type", "echo", "no_such_command_here", "cd"]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+
+        assert_eq!(BuiltinFlow::Continue(1), flow);
+        assert_eq!(
+            "echo is a shell builtin\ncd is a shell builtin\n",
+            String::from_utf8(stdout).unwrap()
+        );
+        assert_eq!(
+            "no_such_command_here: not found\n",
+            String::from_utf8(stderr).unwrap()
+        );
+    }
+
+    #[test]
+    fn cd_enters_directory_with_space_and_newline() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let _guard = CWD_LOCK.lock().unwrap();
+        let saved_dir = SavedDir::new();
+        let root = TempDir::new("cd");
+        let target = root.join("my dir\nname");
+        std::fs::create_dir_all(&target).unwrap();
+
+        let flow = Builtins::builtin_cd(
+            &mut shell,
+            &parts(&["cd", target.to_str().unwrap()]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+        let landed_in = env::current_dir().unwrap();
+        let expected = target.canonicalize().unwrap();
+        saved_dir.restore();
+
+        assert_eq!(BuiltinFlow::Continue(0), flow);
+        assert!(stderr.is_empty());
+        assert_eq!(expected, landed_in);
+    }
+
+    #[test]
+    fn cd_jumps_to_recent_directory_by_number() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let _guard = CWD_LOCK.lock().unwrap();
+        let saved_dir = SavedDir::new();
+        let temp = TempDir::new("cd_recent");
+        let root = temp.canonicalize().unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::create_dir_all(root.join(name)).unwrap();
+            Builtins::builtin_cd(
+                &mut shell,
+                &parts(&["cd", root.join(name).to_str().unwrap()]),
+                &mut stdout,
+                &mut stderr,
+            )
+            .unwrap();
+        }
+
+        Builtins::builtin_cd(
+            &mut shell,
+            &parts(&["cd", "--", "2"]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+        let landed_in = env::current_dir().unwrap();
+        Builtins::builtin_cd(
+            &mut shell,
+            &parts(&["cd", "--", root.join("c").to_str().unwrap()]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+        let after_double_dash = env::current_dir().unwrap();
+        saved_dir.restore();
+
+        assert!(stderr.is_empty());
+        assert_eq!(root.join("a"), landed_in);
+        assert_eq!(root.join("c"), after_double_dash);
+        assert_eq!(
+            vec![root.join("a"), root.join("c"), root.join("b")],
+            shell.recent_dirs[..3].to_vec()
+        );
+    }
+
+    #[test]
+    fn cd_dash_toggles_between_directories() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let _guard = CWD_LOCK.lock().unwrap();
+        let saved_dir = SavedDir::new();
+        let temp = TempDir::new("cd_dash");
+        std::fs::create_dir_all(temp.join("first")).unwrap();
+        std::fs::create_dir_all(temp.join("second")).unwrap();
+        let root = temp.canonicalize().unwrap();
+
+        let mut visited = Vec::new();
+        for args in [
+            ["cd", root.join("first").to_str().unwrap()],
+            ["cd", root.join("second").to_str().unwrap()],
+            ["cd", "-"],
+            ["cd", "-"],
+        ] {
+            Builtins::builtin_cd(&mut shell, &parts(&args), &mut stdout, &mut stderr).unwrap();
+            visited.push(env::current_dir().unwrap());
+        }
+        let pwd_vars = (env::var("PWD").unwrap(), env::var("OLDPWD").unwrap());
+        saved_dir.restore();
+
+        assert!(stderr.is_empty());
+        assert_eq!(root.join("first"), visited[2]);
+        assert_eq!(root.join("second"), visited[3]);
+        assert_eq!(
+            (
+                root.join("second").display().to_string(),
+                root.join("first").display().to_string()
+            ),
+            pwd_vars
+        );
+        assert_eq!(
+            format!(
+                "{}\n{}\n",
+                root.join("first").display(),
+                root.join("second").display()
+            ),
+            String::from_utf8(stdout).unwrap()
+        );
+    }
+
+    #[test]
+    fn cd_dot_dot_uses_the_real_directory_when_pwd_is_stale() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let _guard = CWD_LOCK.lock().unwrap();
+        let saved_dir = SavedDir::new();
+        let temp = TempDir::new("cd_stale");
+        std::fs::create_dir_all(temp.join("inner")).unwrap();
+        std::fs::create_dir_all(temp.join("other/deep")).unwrap();
+        let root = temp.canonicalize().unwrap();
+
+        let mut landed = Vec::new();
+        for stale in ["/nonexistent/stale/path".into(), root.join("other/deep")] {
+            env::set_current_dir(root.join("inner")).unwrap();
+            env::set_var("PWD", stale);
+            Builtins::builtin_cd(&mut shell, &parts(&["cd", ".."]), &mut stdout, &mut stderr)
+                .unwrap();
+            landed.push((env::current_dir().unwrap(), env::var("PWD").unwrap()));
+        }
+        saved_dir.restore();
+
+        assert!(stderr.is_empty());
+        let expected = (root.clone(), root.display().to_string());
+        assert_eq!(vec![expected.clone(), expected], landed);
+    }
+
+    #[test]
+    fn cd_dot_dot_follows_pwd_back_out_of_a_symlink() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let _guard = CWD_LOCK.lock().unwrap();
+        let saved_dir = SavedDir::new();
+        let temp = TempDir::new("cd_logical");
+        std::fs::create_dir_all(temp.join("real/inner")).unwrap();
+        let root = temp.canonicalize().unwrap();
+        std::os::unix::fs::symlink(root.join("real/inner"), root.join("link")).unwrap();
+
+        Builtins::builtin_cd(
+            &mut shell,
+            &parts(&["cd", root.join("link").to_str().unwrap()]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+        let inside = env::var("PWD").unwrap();
+        Builtins::builtin_cd(&mut shell, &parts(&["cd", ".."]), &mut stdout, &mut stderr).unwrap();
+        let landed = (env::current_dir().unwrap(), env::var("PWD").unwrap());
+        saved_dir.restore();
+
+        assert!(stderr.is_empty());
+        assert_eq!(root.join("link").display().to_string(), inside);
+        assert_eq!((root.clone(), root.display().to_string()), landed);
+    }
+
+    #[test]
+    fn export_sets_and_lists_variables() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+
+        Builtins::builtin_export(
+            &mut shell,
+            &parts(&[
+                "export",
+                "EXPORT_TEST_VAR=some value",
+                "EXPORT_QUOTED_VAR=say \"hi\" to $USER `id` \\",
+                "1abc=x",
+            ]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+        Builtins::builtin_export(&mut shell, &parts(&["export"]), &mut stdout, &mut stderr)
+            .unwrap();
+
+        assert_eq!("some value", env::var("EXPORT_TEST_VAR").unwrap());
+        let listing = String::from_utf8(stdout).unwrap();
+        assert!(listing.contains("declare -x EXPORT_TEST_VAR=\"some value\"\n"));
+        assert!(listing.contains(
+            "declare -x EXPORT_QUOTED_VAR=\"say \\\"hi\\\" to \\$USER \\`id\\` \\\\\"\n"
+        ));
+        assert_eq!(
+            "export: 1abc: not a valid identifier\n",
+            String::from_utf8(stderr).unwrap()
+        );
+        env::remove_var("EXPORT_TEST_VAR");
+        env::remove_var("EXPORT_QUOTED_VAR");
+    }
+
+    #[test]
+    fn exit_wraps_status_to_a_byte() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+
+        for (arg, expected) in [("256", 0), ("257", 1), ("-1", 255), ("42", 42)] {
+            let flow = Builtins::builtin_exit(
+                &mut shell,
+                &parts(&["exit", arg]),
+                &mut stdout,
+                &mut stderr,
+            )
+            .unwrap();
+            assert_eq!(BuiltinFlow::Exit(expected), flow);
+        }
+        assert!(stderr.is_empty());
+    }
+
+    #[test]
+    fn echo_e_stops_output_at_backslash_c() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+
+        Builtins::builtin_echo(
+            &mut shell,
+            &parts(&["echo", "-e", "abc\\cdef", "ghi"]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+
+        assert_eq!("abc", String::from_utf8(stdout).unwrap());
+    }
+
+    #[test]
+    fn echo_handles_flags_and_escapes() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+
+        for args in [
+            &["echo", "-n", "no newline"][..],
+            &["echo", "-ne", "a\\tb\\x41\\0101\\n"],
+            &["echo", "-E", "raw\\n"],
+            &["echo", "-x", "-"],
+        ] {
+            Builtins::builtin_echo(&mut shell, &parts(args), &mut stdout, &mut stderr).unwrap();
+        }
+
+        assert_eq!(
+            "no newlinea\tbAA\nraw\\n\n-x -\n",
+            String::from_utf8(stdout).unwrap()
+        );
+    }
+
+    #[test]
+    fn unset_removes_variables_and_skips_invalid_names() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        env::set_var("UNSET_TEST_A", "a");
+        env::set_var("UNSET_TEST_B", "b");
+
+        Builtins::builtin_unset(
+            &mut shell,
+            &parts(&[
+                "unset",
+                "UNSET_TEST_A",
+                "1abc",
+                "UNSET_TEST_B",
+                "UNSET_TEST_MISSING",
+            ]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+
+        assert!(env::var("UNSET_TEST_A").is_err());
+        assert!(env::var("UNSET_TEST_B").is_err());
+        assert_eq!(
+            "unset: 1abc: not a valid identifier\n",
+            String::from_utf8(stderr).unwrap()
+        );
+    }
+
+    #[test]
+    fn history_lists_tails_and_clears_entries() {
+        let mut shell = Shell::new();
+        shell.history = parts(&["echo one", "pwd", "history 2"]);
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        Builtins::builtin_history(&mut shell, &parts(&["history"]), &mut stdout, &mut stderr)
+            .unwrap();
+        assert_eq!(
+            "    1  echo one\n    2  pwd\n    3  history 2\n",
+            String::from_utf8(stdout).unwrap()
+        );
+
+        let mut stdout = Vec::new();
+        Builtins::builtin_history(
+            &mut shell,
+            &parts(&["history", "2"]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+        assert_eq!(
+            "    2  pwd\n    3  history 2\n",
+            String::from_utf8(stdout).unwrap()
+        );
+
+        Builtins::builtin_history(
+            &mut shell,
+            &parts(&["history", "-c"]),
+            &mut Vec::new(),
+            &mut stderr,
+        )
+        .unwrap();
+        assert!(shell.history.is_empty());
+        assert!(stderr.is_empty());
+    }
+
+    #[test]
+    fn source_runs_lines_in_the_current_shell() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let root = TempDir::new("source");
+        let script = root.join("script.sh");
+        std::fs::write(
+            &script,
+            "export SOURCE_TEST_VAR=kept\nexit 4\nexport SOURCE_TEST_AFTER=ran\n",
+        )
+        .unwrap();
+
+        let flow = Builtins::builtin_source(
+            &mut shell,
+            &parts(&[".", script.to_str().unwrap()]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+        let missing = Builtins::builtin_source(
+            &mut shell,
+            &parts(&["source", "/no/such/script.sh"]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+
+        assert_eq!(BuiltinFlow::Exit(4), flow);
+        assert_eq!(Ok("kept".to_string()), env::var("SOURCE_TEST_VAR"));
+        assert!(env::var("SOURCE_TEST_AFTER").is_err());
+        assert_eq!(BuiltinFlow::Continue(1), missing);
+        assert_eq!(
+            "source: /no/such/script.sh: No such file or directory\n",
+            String::from_utf8(stderr).unwrap()
+        );
+    }
+
+    #[test]
+    fn set_toggles_and_reports_pipefail() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+
+        for args in [["set", "-o", "pipefail"], ["set", "-o", "bogus"]] {
+            Builtins::builtin_set(&mut shell, &parts(&args), &mut stdout, &mut stderr).unwrap();
+        }
+        Builtins::builtin_set(&mut shell, &parts(&["set", "-o"]), &mut stdout, &mut stderr)
+            .unwrap();
+        let enabled = shell.pipefail;
+        Builtins::builtin_set(
+            &mut shell,
+            &parts(&["set", "+o", "pipefail"]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+
+        assert!(enabled);
+        assert!(!shell.pipefail);
+        assert_eq!("pipefail\ton\n", String::from_utf8(stdout).unwrap());
+        assert_eq!(
+            "set: bogus: invalid option name\n",
+            String::from_utf8(stderr).unwrap()
+        );
+    }
+
+    #[test]
+    fn alias_defines_lists_and_unalias_removes() {
+        let mut shell = Shell::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+
+        Builtins::builtin_alias(
+            &mut shell,
+            &parts(&["alias", "ll=ls -la", "say=echo 'hi'"]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+        Builtins::builtin_alias(&mut shell, &parts(&["alias"]), &mut stdout, &mut stderr).unwrap();
+        let flow = Builtins::builtin_unalias(
+            &mut shell,
+            &parts(&["unalias", "ll", "missing"]),
+            &mut stdout,
+            &mut stderr,
+        )
+        .unwrap();
+
+        assert_eq!(BuiltinFlow::Continue(1), flow);
+        assert_eq!(
+            "alias ll='ls -la'\nalias say='echo '\\''hi'\\'''\n",
+            String::from_utf8(stdout).unwrap()
+        );
+        assert_eq!(
+            "unalias: missing: not found\n",
+            String::from_utf8(stderr).unwrap()
+        );
+        assert_eq!(vec!["say"], shell.aliases.keys().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bare_cd_goes_home_or_reports_missing_home() {
+        let mut shell = Shell::new();
+        let mut stderr = Vec::new();
+        let _guard = CWD_LOCK.lock().unwrap();
+        let saved_dir = SavedDir::new();
+        let home = env::temp_dir().canonicalize().unwrap();
+
+        let went_home =
+            Builtins::cd_home(&mut shell, Some(home.display().to_string()), &mut stderr).unwrap();
+        let visited = env::current_dir().unwrap();
+        let unset = Builtins::cd_home(&mut shell, None, &mut stderr).unwrap();
+        saved_dir.restore();
+
+        assert_eq!(BuiltinFlow::Continue(0), went_home);
+        assert_eq!(home, visited);
+        assert_eq!(BuiltinFlow::Continue(1), unset);
+        assert_eq!("cd: HOME not set\n", String::from_utf8(stderr).unwrap());
+    }
 }
@@ -0,0 +1,310 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use crate::builtins::Builtins;
+use crate::glob::unescape;
+use crate::utils::is_executable_file;
+
+// Characters the parser would read as syntax if a filename were inserted unescaped.
+const SHELL_SPECIAL: &str = " \t\\'\"`$&|;<>()*?[]{}#~!";
+
+pub fn complete_line(line: &str, builtins: &Builtins) -> Vec<String> {
+    let word = current_word(line);
+    if word.len() == line.trim_start().len() {
+        complete_command(word, builtins)
+    } else if word.contains('@') {
+        complete_host(word)
+    } else {
+        complete_path(word)
+    }
+}
+
+// Escaped whitespace, as inserted by path completion, stays inside the word.
+pub fn current_word(line: &str) -> &str {
+    let mut start = 0;
+    let mut escaped = false;
+    for (index, ch) in line.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if ch == '\\' {
+            escaped = true;
+        } else if ch.is_whitespace() {
+            start = index + ch.len_utf8();
+        }
+    }
+    &line[start..]
+}
+
+pub fn complete_command(prefix: &str, builtins: &Builtins) -> Vec<String> {
+    let path_var = env::var("PATH").unwrap_or_default();
+    complete_command_in(&path_var, prefix, builtins.names())
+}
+
+fn complete_command_in<'a>(
+    path_var: &str,
+    prefix: &str,
+    builtin_names: impl Iterator<Item = &'a str>,
+) -> Vec<String> {
+    let mut candidates: Vec<String> = builtin_names
+        .filter(|name| name.starts_with(prefix))
+        .map(String::from)
+        .collect();
+    for path in path_var.split(':') {
+        let Ok(entries) = fs::read_dir(path) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(prefix) && is_executable_file(&entry.path()) {
+                candidates.push(name);
+            }
+        }
+    }
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+pub fn complete_path(word: &str) -> Vec<String> {
+    let path = unescape(word);
+    let (dir_part, file_prefix) = match path.rfind('/') {
+        Some(index) => path.split_at(index + 1),
+        None => ("", path.as_str()),
+    };
+    let dir = if dir_part.is_empty() { "." } else { dir_part };
+    let Ok(entries) = fs::read_dir(Path::new(dir)) else {
+        return Vec::new();
+    };
+
+    let mut candidates = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(file_prefix)
+            || (name.starts_with('.') && !file_prefix.starts_with('.'))
+        {
+            continue;
+        }
+        // Extend the word as typed so the candidates keep it as their prefix.
+        let mut candidate = format!("{}{}", word, escape_path(&name[file_prefix.len()..]));
+        if entry.path().is_dir() {
+            candidate.push('/');
+        }
+        candidates.push(candidate);
+    }
+    candidates.sort();
+    candidates
+}
+
+fn escape_path(name: &str) -> String {
+    let mut escaped = String::new();
+    for ch in name.chars() {
+        if SHELL_SPECIAL.contains(ch) {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+pub fn complete_host(word: &str) -> Vec<String> {
+    let known_hosts = env::var("HOME")
+        .map(|home| Path::new(&home).join(".ssh/known_hosts"))
+        .unwrap_or_default();
+    complete_host_in(word, &known_hosts, Path::new("/etc/hosts"))
+}
+
+fn complete_host_in(word: &str, known_hosts: &Path, hosts: &Path) -> Vec<String> {
+    let (user_part, host_prefix) = word.split_at(word.rfind('@').map_or(0, |index| index + 1));
+    let mut names = Vec::new();
+    if let Ok(contents) = fs::read_to_string(known_hosts) {
+        names.extend(contents.lines().flat_map(known_host_names));
+    }
+    if let Ok(contents) = fs::read_to_string(hosts) {
+        names.extend(contents.lines().flat_map(etc_host_names));
+    }
+
+    let mut candidates: Vec<String> = names
+        .into_iter()
+        .filter(|name| name.starts_with(host_prefix))
+        .map(|name| format!("{}{}", user_part, name))
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+// Hashed entries and wildcard patterns can't be offered as hostnames.
+fn known_host_names(line: &str) -> Vec<String> {
+    let mut fields = line.split_whitespace();
+    let mut patterns = fields.next().unwrap_or_default();
+    if patterns.starts_with('@') {
+        patterns = fields.next().unwrap_or_default();
+    }
+    patterns
+        .split(',')
+        .filter(|pattern| !pattern.starts_with('|') && !pattern.contains(['*', '?', '!']))
+        .map(|pattern| match pattern.strip_prefix('[') {
+            Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+            None => pattern,
+        })
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn etc_host_names(line: &str) -> Vec<String> {
+    let line = line.split('#').next().unwrap_or_default();
+    line.split_whitespace().skip(1).map(String::from).collect()
+}
+
+pub fn completion_suffix(word: &str, candidates: &[String]) -> Option<String> {
+    match candidates {
+        [] => None,
+        // Directories stay open so the next Tab can descend into them.
+        [only] if only.ends_with('/') => Some(only[word.len()..].to_string()),
+        [only] => Some(format!("{} ", &only[word.len()..])),
+        [first, rest @ ..] => {
+            let mut common = first.as_str();
+            for candidate in rest {
+                let shared = common
+                    .char_indices()
+                    .zip(candidate.chars())
+                    .find(|((_, a), b)| a != b)
+                    .map_or(common.len().min(candidate.len()), |((index, _), _)| index);
+                common = &common[..shared];
+            }
+            if common.len() > word.len() {
+                Some(common[word.len()..].to_string())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn completes_builtins_and_path_executables() {
+        let root = TempDir::new("complete");
+        for name in ["exotic_tool", "exotic_plain"] {
+            fs::write(root.join(name), "#!/bin/sh\n").unwrap();
+        }
+        fs::set_permissions(root.join("exotic_tool"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        let candidates = complete_command_in(
+            &root.display().to_string(),
+            "ex",
+            ["exit", "export", "echo"].into_iter(),
+        );
+
+        assert_eq!(vec!["exit", "exotic_tool", "export"], candidates);
+    }
+
+    #[test]
+    fn completes_paths_relative_to_the_partial_directory() {
+        let root = TempDir::new("complete_path");
+        fs::create_dir_all(root.join("notes_dir")).unwrap();
+        fs::write(root.join("notes.txt"), "").unwrap();
+        fs::write(root.join(".notes_hidden"), "").unwrap();
+        fs::write(root.join("other.txt"), "").unwrap();
+
+        let prefix = format!("{}/no", root.display());
+        let candidates = complete_path(&prefix);
+
+        assert_eq!(
+            vec![format!("{}tes.txt", prefix), format!("{}tes_dir/", prefix)],
+            candidates
+        );
+        assert_eq!(
+            Some("tes".to_string()),
+            completion_suffix(&prefix, &candidates)
+        );
+        assert_eq!(
+            Some("tes_dir/".to_string()),
+            completion_suffix(&prefix, &candidates[1..])
+        );
+    }
+
+    #[test]
+    fn escapes_special_characters_in_path_completions() {
+        let root = TempDir::new("complete_escape");
+        fs::write(root.join("c d.txt"), "").unwrap();
+        fs::write(root.join("$x;y"), "").unwrap();
+
+        let prefix = format!("{}/c", root.display());
+        let spaced = complete_path(&prefix);
+        let typed = complete_path(&format!("{}\\ d", prefix));
+        let special = complete_path(&format!("{}/\\$", root.display()));
+
+        assert_eq!(vec![format!("{}\\ d.txt", prefix)], spaced);
+        assert_eq!(
+            Some("\\ d.txt ".to_string()),
+            completion_suffix(&prefix, &spaced)
+        );
+        assert_eq!(vec![format!("{}\\ d.txt", prefix)], typed);
+        assert_eq!(vec![format!("{}/\\$x\\;y", root.display())], special);
+        assert_eq!("c\\ d", current_word("cat c\\ d"));
+    }
+
+    #[test]
+    fn completes_hostnames_after_an_at_sign() {
+        let root = TempDir::new("complete_host");
+        fs::write(
+            root.join("known_hosts"),
+            "build.example.com,10.0.0.5 ssh-ed25519 AAAA\n\
+             [bastion.example.com]:2222 ssh-rsa AAAA\n\
+             |1|hashed= ssh-rsa AAAA\n\
+             @cert-authority *.example.com ssh-rsa AAAA\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("hosts"),
+            "# local names\n127.0.0.1 localhost\n10.0.0.9 backup.example.com backup\n",
+        )
+        .unwrap();
+
+        let candidates =
+            complete_host_in("deploy@b", &root.join("known_hosts"), &root.join("hosts"));
+        let missing = complete_host_in("me@", &root.join("absent"), &root.join("absent"));
+
+        assert_eq!(
+            vec![
+                "deploy@backup",
+                "deploy@backup.example.com",
+                "deploy@bastion.example.com",
+                "deploy@build.example.com",
+            ],
+            candidates
+        );
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn only_the_first_word_completes_commands() {
+        assert_eq!("ec", current_word("ec"));
+        assert_eq!("src/ma", current_word("cat src/ma"));
+        assert_eq!("", current_word("cat "));
+    }
+
+    #[test]
+    fn suffix_fills_single_match_or_common_prefix() {
+        let names = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            Some("ho ".to_string()),
+            completion_suffix("ec", &names(&["echo"]))
+        );
+        assert_eq!(
+            Some("port".to_string()),
+            completion_suffix("ex", &names(&["export", "exported"]))
+        );
+        assert_eq!(None, completion_suffix("ex", &names(&["exit", "export"])));
+        assert_eq!(None, completion_suffix("zz", &[]));
+    }
+}
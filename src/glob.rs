@@ -0,0 +1,255 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+pub const GLOB_CHARS: [char; 3] = ['*', '?', '['];
+
+pub fn expand_glob(pattern: &str) -> Vec<String> {
+    expand_ignoring(pattern, &env::var("GLOBIGNORE").unwrap_or_default())
+}
+
+// Like bash, a non-empty GLOBIGNORE also lets wildcards match dot files.
+fn expand_ignoring(pattern: &str, globignore: &str) -> Vec<String> {
+    let wants_dirs = pattern.ends_with('/');
+    let mut bases = vec![if pattern.starts_with('/') {
+        "/".to_string()
+    } else {
+        String::new()
+    }];
+
+    for component in pattern.split('/').filter(|c| !c.is_empty()) {
+        let mut next = Vec::new();
+        for base in &bases {
+            if !has_glob_chars(component) {
+                let candidate = join(base, &unescape(component));
+                if fs::symlink_metadata(&candidate).is_ok() {
+                    next.push(candidate);
+                }
+                continue;
+            }
+
+            let dir = if base.is_empty() { "." } else { base.as_str() };
+            // An unreadable directory just contributes no matches.
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let shows_hidden = component.starts_with('.')
+                    || component.starts_with("\\.")
+                    || !globignore.is_empty();
+                if (name.starts_with('.') && !shows_hidden) || !matches(component, &name) {
+                    continue;
+                }
+                next.push(join(base, &name));
+            }
+        }
+        bases = next;
+    }
+
+    if wants_dirs {
+        bases.retain(|path| Path::new(path).is_dir());
+        for path in &mut bases {
+            path.push('/');
+        }
+    }
+    if !globignore.is_empty() {
+        bases.retain(|path| !is_ignored(path, globignore));
+    }
+    if bases.is_empty() {
+        return vec![unescape(pattern)];
+    }
+    bases.sort();
+    bases
+}
+
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches_from(&pattern, &text)
+}
+
+pub fn unescape(pattern: &str) -> String {
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => text.push(chars.next().unwrap_or('\\')),
+            _ => text.push(ch),
+        }
+    }
+    text
+}
+
+// . and .. are always left out once GLOBIGNORE is set, whatever it lists.
+fn is_ignored(path: &str, globignore: &str) -> bool {
+    let name = path
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    name == "."
+        || name == ".."
+        || globignore
+            .split(':')
+            .any(|ignored| !ignored.is_empty() && matches(ignored, path))
+}
+
+fn has_glob_chars(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            ch if GLOB_CHARS.contains(&ch) => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn join(base: &str, name: &str) -> String {
+    if base.is_empty() || base.ends_with('/') {
+        format!("{}{}", base, name)
+    } else {
+        format!("{}/{}", base, name)
+    }
+}
+
+fn matches_from(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            (0..=text.len()).any(|skip| matches_from(rest, &text[skip..]))
+        }
+        Some('?') => !text.is_empty() && matches_from(&pattern[1..], &text[1..]),
+        Some('[') => {
+            let Some(&ch) = text.first() else {
+                return false;
+            };
+            match match_bracket(&pattern[1..], ch) {
+                Some((matched, used)) => matched && matches_from(&pattern[1 + used..], &text[1..]),
+                // An unterminated bracket is an ordinary character.
+                None => ch == '[' && matches_from(&pattern[1..], &text[1..]),
+            }
+        }
+        Some('\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && matches_from(&pattern[2..], &text[1..])
+        }
+        Some(ch) => text.first() == Some(ch) && matches_from(&pattern[1..], &text[1..]),
+    }
+}
+
+fn match_bracket(class: &[char], ch: char) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some('!' | '^'));
+    let mut index = usize::from(negated);
+    let mut matched = false;
+    let mut first = true;
+    while index < class.len() {
+        if class[index] == ']' && !first {
+            return Some((matched != negated, index + 1));
+        }
+        first = false;
+        if class[index] == '\\' && index + 1 < class.len() {
+            index += 1;
+        }
+        let start = class[index];
+        match (class.get(index + 1), class.get(index + 2)) {
+            (Some('-'), Some(&end)) if end != ']' => {
+                matched |= start <= ch && ch <= end;
+                index += 3;
+            }
+            _ => {
+                matched |= start == ch;
+                index += 1;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn matches_wildcards_and_brackets() {
+        assert!(matches("*.txt", "notes.txt"));
+        assert!(!matches("*.txt", "notes.rs"));
+        assert!(matches("a?c", "abc"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[!a-c]x", "bx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("[abc", "[abc"));
+    }
+
+    #[test]
+    fn expands_sorted_matches_or_keeps_the_pattern() {
+        let root = TempDir::new("glob");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        for name in ["b.txt", "a.txt", "c.rs", ".hidden.txt", "sub/d.txt"] {
+            fs::write(root.join(name), "").unwrap();
+        }
+        let base = root.display().to_string();
+
+        let top = expand_glob(&format!("{}/*.txt", base));
+        let nested = expand_glob(&format!("{}/*/*.txt", base));
+        let dirs = expand_glob(&format!("{}/*/", base));
+        let missing = expand_glob(&format!("{}/*.md", base));
+
+        assert_eq!(
+            vec![format!("{}/a.txt", base), format!("{}/b.txt", base)],
+            top
+        );
+        assert_eq!(vec![format!("{}/sub/d.txt", base)], nested);
+        assert_eq!(vec![format!("{}/sub/", base)], dirs);
+        assert_eq!(vec![format!("{}/*.md", base)], missing);
+    }
+
+    #[test]
+    fn globignore_drops_matching_results() {
+        let root = TempDir::new("globignore");
+        for name in ["a.txt", "b.tmp", ".hidden"] {
+            fs::write(root.join(name), "").unwrap();
+        }
+        let base = root.display().to_string();
+
+        let kept = expand_ignoring(&format!("{}/*", base), "*.tmp");
+        let all_ignored = expand_ignoring(&format!("{}/*.tmp", base), "*.txt:*.tmp");
+
+        assert_eq!(
+            vec![format!("{}/.hidden", base), format!("{}/a.txt", base)],
+            kept
+        );
+        assert!(is_ignored("sub/..", "*.tmp") && is_ignored(".", "*.tmp"));
+        assert_eq!(vec![format!("{}/*.tmp", base)], all_ignored);
+    }
+
+    #[test]
+    fn unreadable_directories_contribute_no_matches() {
+        let root = TempDir::new("glob_unreadable");
+        for dir in ["open", "locked"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("file.txt"), "").unwrap();
+        }
+        let locked = root.join("locked");
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        let base = root.display().to_string();
+
+        let expanded = expand_glob(&format!("{}/*/*.txt", base));
+        // Put the mode back first so the directory can still be cleaned up.
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert!(expanded.contains(&format!("{}/open/file.txt", base)));
+        // Root can read a mode 000 directory, so only other users see it skipped.
+        if unsafe { libc::geteuid() } != 0 {
+            assert_eq!(vec![format!("{}/open/file.txt", base)], expanded);
+        }
+    }
+}
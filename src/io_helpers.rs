@@ -1,5 +1,6 @@
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
+use std::os::fd::{AsFd, FromRawFd};
 
 use crate::parser::RedirectType;
 
@@ -7,7 +8,6 @@ pub fn get_write_output(redirect_filename: &str, redirect_type: RedirectType) ->
     match redirect_type {
         RedirectType::APPEND => OpenOptions::new()
             .create(true)
-            .write(true)
             .append(true)
             .open(redirect_filename),
         RedirectType::CREATE => OpenOptions::new()
@@ -15,7 +15,34 @@ pub fn get_write_output(redirect_filename: &str, redirect_type: RedirectType) ->
             .write(true)
             .truncate(true)
             .open(redirect_filename),
+        RedirectType::READ => get_read_input(redirect_filename),
+        RedirectType::DUP(fd) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("fd {} is duplicated, not opened", fd),
+        )),
+    }
+}
+
+pub fn get_read_input(redirect_filename: &str) -> io::Result<File> {
+    OpenOptions::new().read(true).open(redirect_filename)
+}
+
+pub fn create_pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    // Children only get these ends through an explicit Stdio, never by inheritance.
+    // Setting O_CLOEXEC atomically keeps a fork on another thread from leaking them.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
     }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+pub fn duplicate_std_fd(fd: u32) -> io::Result<File> {
+    let owned = match fd {
+        1 => io::stdout().as_fd().try_clone_to_owned()?,
+        _ => io::stderr().as_fd().try_clone_to_owned()?,
+    };
+    Ok(File::from(owned))
 }
 
 pub enum OutputSink<'a> {
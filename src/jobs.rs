@@ -0,0 +1,123 @@
+pub struct Job {
+    pub id: usize,
+    pub command: String,
+    pids: Vec<libc::pid_t>,
+    last_pid: libc::pid_t,
+}
+
+impl Job {
+    pub fn pid(&self) -> u32 {
+        self.last_pid as u32
+    }
+
+    // Reaped pids are dropped, so a pid the kernel hands out again is never waited on.
+    fn is_done(&mut self) -> bool {
+        self.pids.retain(|&pid| {
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) == 0 }
+        });
+        self.pids.is_empty()
+    }
+}
+
+#[derive(Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+}
+
+impl JobTable {
+    pub fn add(&mut self, pids: Vec<libc::pid_t>, command: &str) -> &Job {
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        self.jobs.push(Job {
+            id,
+            command: command.to_string(),
+            last_pid: pids.last().copied().unwrap_or(0),
+            pids,
+        });
+        &self.jobs[self.jobs.len() - 1]
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    #[cfg(test)]
+    pub fn pids(&self) -> Vec<u32> {
+        self.jobs.iter().map(Job::pid).collect()
+    }
+
+    pub fn reap(&mut self) -> Vec<String> {
+        self.report(false)
+    }
+
+    // Like bash's jobs: running jobs are listed too, finished ones are reported once.
+    pub fn list(&mut self) -> Vec<String> {
+        self.report(true)
+    }
+
+    fn report(&mut self, include_running: bool) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut index = 0;
+        while index < self.jobs.len() {
+            let marker = self.marker(index);
+            if !self.jobs[index].is_done() {
+                let job = &self.jobs[index];
+                if include_running {
+                    let command = format!("{} &", job.command);
+                    lines.push(status_line(job.id, marker, "Running", &command));
+                }
+                index += 1;
+                continue;
+            }
+            let job = self.jobs.remove(index);
+            lines.push(status_line(job.id, marker, "Done", &job.command));
+        }
+        lines
+    }
+
+    // The newest job is the current one (+) and the one before it is the previous one (-).
+    fn marker(&self, index: usize) -> char {
+        match self.jobs.len() - index {
+            1 => '+',
+            2 => '-',
+            _ => ' ',
+        }
+    }
+}
+
+fn status_line(id: usize, marker: char, status: &str, command: &str) -> String {
+    format!("[{}]{}  {:<24}{}", id, marker, status, command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn reaps_finished_jobs_and_keeps_running_ones() {
+        let mut jobs = JobTable::default();
+        let quick = Command::new("true").spawn().unwrap().id() as libc::pid_t;
+        let slow = Command::new("sleep").arg("5").spawn().unwrap().id() as libc::pid_t;
+        assert_eq!(1, jobs.add(vec![quick], "true").id);
+        assert_eq!(2, jobs.add(vec![slow], "sleep 5").id);
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(
+            vec![
+                "[1]-  Done                    true".to_string(),
+                "[2]+  Running                 sleep 5 &".to_string(),
+            ],
+            jobs.list()
+        );
+        assert!(jobs.reap().is_empty());
+        assert_eq!(3, jobs.add(Vec::new(), "next").id);
+
+        unsafe {
+            libc::kill(slow, libc::SIGKILL);
+            libc::waitpid(slow, std::ptr::null_mut(), 0);
+        }
+    }
+}
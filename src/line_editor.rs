@@ -0,0 +1,220 @@
+use std::io::{self, Write};
+use std::mem;
+
+use crate::completion::{completion_suffix, current_word};
+
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const BELL: &[u8] = b"\x07";
+const ESCAPE: u8 = 0x1b;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const ESCAPE_TIMEOUT_MS: i32 = 50;
+
+pub fn read_line(
+    buf: &mut String,
+    complete: &mut dyn FnMut(&str) -> Vec<String>,
+) -> io::Result<usize> {
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+        return io::stdin().read_line(buf);
+    }
+
+    let _raw_mode = RawMode::enable()?;
+    let mut stdout = io::stdout().lock();
+    let mut line: Vec<u8> = Vec::new();
+    let mut byte = [0u8; 1];
+    let mut pending = None;
+    loop {
+        let read = match pending.take() {
+            Some(pending) => {
+                byte[0] = pending;
+                Ok(1)
+            }
+            None => read_stdin(&mut byte),
+        };
+        let read = match read {
+            Ok(read) => read,
+            // Ctrl-C abandons the line, like bash.
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                stdout.write_all(b"^C\n")?;
+                line.clear();
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        if read == 0 {
+            if line.is_empty() {
+                return Ok(0);
+            }
+            break;
+        }
+        match byte[0] {
+            b'\r' | b'\n' => {
+                stdout.write_all(b"\n")?;
+                break;
+            }
+            CTRL_D if line.is_empty() => {
+                stdout.write_all(b"\n")?;
+                return Ok(0);
+            }
+            CTRL_C => {
+                stdout.write_all(b"^C\n")?;
+                line.clear();
+                break;
+            }
+            BACKSPACE | DELETE => {
+                let start = line.len().saturating_sub(1);
+                rub_out(&mut line, start, &mut stdout)?;
+            }
+            CTRL_U => rub_out(&mut line, 0, &mut stdout)?,
+            CTRL_W => {
+                let start = previous_word_start(&line);
+                rub_out(&mut line, start, &mut stdout)?;
+            }
+            ESCAPE => {
+                // Arrow and other escape sequences are skipped; a lone Esc does nothing.
+                if stdin_ready() && read_stdin(&mut byte)? == 1 {
+                    match byte[0] {
+                        b'[' => {
+                            while stdin_ready()
+                                && read_stdin(&mut byte)? == 1
+                                && !(0x40..=0x7e).contains(&byte[0])
+                            {}
+                        }
+                        b'O' => {
+                            if stdin_ready() {
+                                read_stdin(&mut byte)?;
+                            }
+                        }
+                        other => pending = Some(other),
+                    }
+                }
+            }
+            b'\t' => {
+                let text = String::from_utf8_lossy(&line).into_owned();
+                let candidates = complete(&text);
+                let suffix = completion_suffix(current_word(&text), &candidates);
+                if let Some(suffix) = &suffix {
+                    line.extend_from_slice(suffix.as_bytes());
+                    stdout.write_all(suffix.as_bytes())?;
+                }
+                // Still ambiguous after the common prefix, or nothing to add.
+                if suffix.is_none() || candidates.len() > 1 {
+                    stdout.write_all(BELL)?;
+                }
+            }
+            // Any other control byte, including Ctrl-D on a non-empty line, is ignored.
+            other if other < 0x20 => {}
+            other => {
+                line.push(other);
+                stdout.write_all(&[other])?;
+            }
+        }
+        stdout.flush()?;
+    }
+    stdout.flush()?;
+
+    line.push(b'\n');
+    let line = String::from_utf8_lossy(&line);
+    buf.push_str(&line);
+    Ok(line.len())
+}
+
+// Erases back to `start`, one whole UTF-8 character at a time.
+fn rub_out(line: &mut Vec<u8>, start: usize, stdout: &mut impl Write) -> io::Result<()> {
+    while line.len() > start {
+        // Continuation bytes go first, then the byte that starts the character.
+        while line.pop().is_some_and(|b| b & 0xC0 == 0x80) {}
+        stdout.write_all(b"\x08 \x08")?;
+    }
+    Ok(())
+}
+
+// Like Ctrl-W in a terminal: trailing blanks, then the word before them.
+fn previous_word_start(line: &[u8]) -> usize {
+    let end = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |index| index + 1);
+    line[..end]
+        .iter()
+        .rposition(u8::is_ascii_whitespace)
+        .map_or(0, |index| index + 1)
+}
+
+// Unbuffered, so poll on the descriptor sees every byte that hasn't been read yet.
+fn read_stdin(byte: &mut [u8; 1]) -> io::Result<usize> {
+    let read = unsafe { libc::read(libc::STDIN_FILENO, byte.as_mut_ptr().cast(), 1) };
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(read as usize)
+}
+
+fn stdin_ready() -> bool {
+    let mut poll_fd = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut poll_fd, 1, ESCAPE_TIMEOUT_MS) > 0 }
+}
+
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        let mut original: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_erase_takes_trailing_blanks_and_one_word() {
+        assert_eq!(5, previous_word_start(b"echo hello  "));
+        assert_eq!(5, previous_word_start(b"echo hello"));
+        assert_eq!(0, previous_word_start(b"echo"));
+        assert_eq!(0, previous_word_start(b"   "));
+    }
+
+    #[test]
+    fn rub_out_erases_whole_characters() {
+        let mut line = "ls caf\u{e9}".as_bytes().to_vec();
+        let mut echoed = Vec::new();
+
+        // Backspace asks for the last byte and gets the whole two-byte character.
+        let last = line.len() - 1;
+        rub_out(&mut line, last, &mut echoed).unwrap();
+        assert_eq!(b"ls caf".to_vec(), line);
+
+        rub_out(&mut line, 3, &mut echoed).unwrap();
+        assert_eq!(b"ls ".to_vec(), line);
+        assert_eq!(b"\x08 \x08".repeat(4), echoed);
+    }
+}
@@ -1,14 +1,57 @@
 mod builtins;
+mod completion;
+mod glob;
 mod io_helpers;
+mod jobs;
+mod line_editor;
 mod parser;
+mod prompt;
 mod shell;
+mod signals;
+mod timing;
 mod utils;
 
+use std::env;
+use std::process;
+
 use crate::shell::Shell;
 
+const VERSION: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+const FEATURES: &str = "pipelines, redirects, command substitution, history";
+const SCRIPT_UNREADABLE: i32 = 127;
+
 fn main() {
+    let mut args = env::args();
+    let mut login = args.next().is_some_and(|argv0| argv0.starts_with('-'));
+    let mut script = None;
+    for arg in args {
+        match arg.as_str() {
+            "--login" | "-l" => login = true,
+            "--version" => {
+                println!("{}", VERSION);
+                println!("features: {}", FEATURES);
+                return;
+            }
+            _ => {
+                script = Some(arg);
+                break;
+            }
+        }
+    }
+
     let mut shell = Shell::new();
-    if let Err(err) = shell.run() {
-        eprintln!("shell error: {}", err);
+    if login || script.is_none() {
+        shell.load_startup_files(login);
+    }
+    let Some(script) = script else {
+        if let Err(err) = shell.run() {
+            eprintln!("shell error: {}", err);
+        }
+        return;
+    };
+    if let Err(err) = shell.run_script(&script) {
+        eprintln!("{}: {}", script, err);
+        process::exit(SCRIPT_UNREADABLE);
     }
+    process::exit(shell.last_status);
 }
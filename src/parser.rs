@@ -1,9 +1,16 @@
+use std::env;
 use std::iter::Peekable;
 
+use crate::glob::{expand_glob, unescape, GLOB_CHARS};
+use crate::utils::expand_tilde;
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone)]
 pub enum RedirectType {
     CREATE,
     APPEND,
+    READ,
+    DUP(u32),
 }
 
 #[derive(Debug, Clone)]
@@ -13,21 +20,278 @@ pub struct Redirect {
     pub redirect_type: RedirectType,
 }
 
-pub fn tokenize(input: &str) -> Result<(Vec<String>, Option<Redirect>), String> {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Word(String),
+    Pattern(String),
+    Operator(String),
+}
+
+const OPERATORS: [&str; 14] = [
+    "||", "|&", "&&", ";;", ">>", "<<", ">&", "|", "&", ";", "<", ">", "(", ")",
+];
+
+pub trait Expand {
+    fn command_output(&mut self, command: &str) -> String;
+    fn special_parameter(&self, name: char) -> Option<String>;
+}
+
+impl<F: FnMut(&str) -> String> Expand for F {
+    fn command_output(&mut self, command: &str) -> String {
+        self(command)
+    }
+
+    fn special_parameter(&self, _name: char) -> Option<String> {
+        None
+    }
+}
+
+const SPECIAL_PARAMETERS: [char; 1] = ['?'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListItem {
+    pub connector: Option<Connector>,
+    pub pipeline: String,
+    pub background: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub args: Vec<String>,
+    pub redirects: Vec<Redirect>,
+}
+
+pub fn tokenize(input: &str, expander: &mut dyn Expand) -> Result<Vec<Command>, String> {
+    let mut commands = Vec::new();
+    let mut segment: Vec<Token> = Vec::new();
+    for token in lex(input, expander) {
+        let both = token == Token::Operator("|&".to_string());
+        if both || token == Token::Operator("|".to_string()) {
+            if segment.is_empty() {
+                return Err("syntax error near unexpected token `|'".to_string());
+            }
+            // |& is 2>&1 | after the stage's own redirects.
+            if both {
+                segment.push(Token::Operator("2>&".to_string()));
+                segment.push(Token::Word("1".to_string()));
+            }
+            commands.push(parse_command(std::mem::take(&mut segment))?);
+        } else {
+            segment.push(token);
+        }
+    }
+    if segment.is_empty() {
+        if !commands.is_empty() {
+            return Err("syntax error near unexpected token `|'".to_string());
+        }
+        return Ok(commands);
+    }
+    commands.push(parse_command(segment)?);
+    Ok(commands)
+}
+
+// Split at top-level &&, ||, ;, & and newlines, leaving each pipeline unexpanded until it runs.
+pub fn split_list(input: &str) -> Result<Vec<ListItem>, String> {
+    let mut items = Vec::new();
+    let mut connector = None;
+    let mut current = String::new();
+    let mut contexts: Vec<char> = Vec::new();
+    let mut input_chars = input.chars().peekable();
+    while let Some(ch) = input_chars.next() {
+        let context = contexts.last().copied();
+        match (context, ch) {
+            (Some('\''), '\'') | (Some('`'), '`') | (Some('"'), '"') | (Some('('), ')') => {
+                contexts.pop();
+            }
+            (Some('\'' | '`'), _) => {}
+            (_, '\\') => {
+                current.push(ch);
+                if let Some(next_char) = input_chars.next() {
+                    current.push(next_char);
+                }
+                continue;
+            }
+            (Some('"'), '$') | (None | Some('('), '$') if input_chars.peek() == Some(&'(') => {
+                current.push(ch);
+                current.push('(');
+                input_chars.next();
+                contexts.push('(');
+                continue;
+            }
+            (Some('"'), '`') | (None | Some('('), '`' | '"' | '\'') => contexts.push(ch),
+            (Some('('), '(') => contexts.push(ch),
+            (None | Some('('), '#') if starts_word(&current) => {
+                skip_comment(&mut input_chars);
+                continue;
+            }
+            (None, '\n') => {
+                if !current.trim().is_empty() {
+                    items.push(ListItem {
+                        connector: connector.take(),
+                        pipeline: take_pipeline(&mut current, "newline")?,
+                        background: false,
+                    });
+                }
+                continue;
+            }
+            (None, '&' | '|') if input_chars.peek() == Some(&ch) => {
+                input_chars.next();
+                let (operator, next_connector) = if ch == '&' {
+                    ("&&", Connector::And)
+                } else {
+                    ("||", Connector::Or)
+                };
+                items.push(ListItem {
+                    connector,
+                    pipeline: take_pipeline(&mut current, operator)?,
+                    background: false,
+                });
+                connector = Some(next_connector);
+                continue;
+            }
+            // A & right after a redirect operator is part of >&N, and after | it is |&.
+            (None, ';' | '&') if !current.ends_with(['>', '<', '|']) => {
+                items.push(ListItem {
+                    connector: connector.take(),
+                    pipeline: take_pipeline(&mut current, &ch.to_string())?,
+                    background: ch == '&',
+                });
+                continue;
+            }
+            _ => {}
+        }
+        current.push(ch);
+    }
+
+    let pipeline = current.trim().to_string();
+    if pipeline.is_empty() {
+        if connector.is_some() {
+            return Err("syntax error: unexpected end of file".to_string());
+        }
+        return Ok(items);
+    }
+    items.push(ListItem {
+        connector,
+        pipeline,
+        background: false,
+    });
+    Ok(items)
+}
+
+// Split a pipeline at its top-level | without expanding anything; |& ends its stage with 2>&1.
+pub fn split_stages(pipeline: &str) -> Vec<String> {
+    let mut stages = Vec::new();
+    let mut start = 0;
+    let mut contexts: Vec<char> = Vec::new();
+    let mut input_chars = pipeline.char_indices().peekable();
+    while let Some((index, ch)) = input_chars.next() {
+        match (contexts.last().copied(), ch) {
+            (Some('\''), '\'') | (Some('`'), '`') | (Some('"'), '"') | (Some('('), ')') => {
+                contexts.pop();
+            }
+            (Some('\'' | '`'), _) => {}
+            (_, '\\') => {
+                input_chars.next();
+            }
+            (None | Some('"' | '('), '$') if input_chars.peek().map(|&(_, c)| c) == Some('(') => {
+                input_chars.next();
+                contexts.push('(');
+            }
+            (Some('"'), '`') | (None | Some('('), '`' | '"' | '\'') => contexts.push(ch),
+            (Some('('), '(') => contexts.push(ch),
+            (None, '|') => {
+                let mut stage = pipeline[start..index].to_string();
+                start = index + 1;
+                if input_chars.next_if(|&(_, c)| c == '&').is_some() {
+                    stage.push_str(" 2>&1 ");
+                    start += 1;
+                }
+                stages.push(stage);
+            }
+            _ => {}
+        }
+    }
+    stages.push(pipeline[start..].to_string());
+    stages
+}
+
+// Strip the leading `!` words of a pipeline; each one inverts its status.
+pub fn strip_negation(pipeline: &str) -> (bool, &str) {
+    let mut negated = false;
+    let mut rest = pipeline.trim_start();
+    while let Some(after) = rest
+        .strip_prefix('!')
+        .filter(|after| after.is_empty() || after.starts_with(char::is_whitespace))
+    {
+        negated = !negated;
+        rest = after.trim_start();
+    }
+    (negated, rest)
+}
+
+fn starts_word(current: &str) -> bool {
+    current.chars().last().map_or(true, |last| {
+        last.is_whitespace() || matches!(last, '(' | ';' | '|')
+    })
+}
+
+// The newline itself is left in place so it still ends the command.
+fn skip_comment(input_chars: &mut Peekable<std::str::Chars<'_>>) {
+    while input_chars.next_if(|&ch| ch != '\n').is_some() {}
+}
+
+fn take_pipeline(current: &mut String, operator: &str) -> Result<String, String> {
+    let pipeline = std::mem::take(current).trim().to_string();
+    if pipeline.is_empty() {
+        return Err(format!("syntax error near unexpected token `{}'", operator));
+    }
+    Ok(pipeline)
+}
+
+fn parse_command(tokens: Vec<Token>) -> Result<Command, String> {
+    let mut args = Vec::new();
+    let mut redirects = Vec::new();
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Word(word) => args.push(word),
+            Token::Pattern(pattern) => args.extend(expand_glob(&pattern)),
+            Token::Operator(op) => {
+                let Some(redirect) = parse_redirect(&op, tokens.next())? else {
+                    return Err(format!("unsupported operator: {}", op));
+                };
+                redirects.push(redirect);
+            }
+        }
+    }
+    Ok(Command { args, redirects })
+}
+
+pub fn lex(input: &str, expander: &mut dyn Expand) -> Vec<Token> {
     let mut current_token = String::new();
-    let mut tokens: Vec<String> = Vec::new();
+    let mut current_token_quoted = false;
+    let mut glob_positions: Vec<usize> = Vec::new();
+    let mut tokens: Vec<Token> = Vec::new();
     let mut input_chars = input.chars().peekable();
     let mut is_in_single_quotes = false;
     let mut is_in_double_quotes = false;
     while let Some(ch) = input_chars.next() {
         match ch {
             '\\' if !is_in_single_quotes => {
+                current_token_quoted = true;
                 handle_escape(&mut current_token, &mut input_chars, is_in_double_quotes)
             }
             '"' => {
                 if is_in_single_quotes {
                     current_token.push(ch);
                 } else {
+                    current_token_quoted = true;
                     is_in_double_quotes = !is_in_double_quotes;
                 }
             }
@@ -35,28 +299,320 @@ pub fn tokenize(input: &str) -> Result<(Vec<String>, Option<Redirect>), String>
                 if is_in_double_quotes {
                     current_token.push(ch);
                 } else {
+                    current_token_quoted = true;
                     is_in_single_quotes = !is_in_single_quotes;
                 }
             }
+            '$' if !is_in_single_quotes => {
+                let value = if input_chars.peek() == Some(&'(') {
+                    input_chars.next();
+                    let command = read_command_substitution(&mut input_chars);
+                    Some(
+                        expander
+                            .command_output(&command)
+                            .trim_end_matches('\n')
+                            .to_string(),
+                    )
+                } else {
+                    read_variable(&mut input_chars, expander)
+                };
+                match value {
+                    Some(value) => {
+                        current_token_quoted = true;
+                        push_expansion(
+                            &mut tokens,
+                            &mut current_token,
+                            &mut glob_positions,
+                            &value,
+                            is_in_double_quotes,
+                        );
+                    }
+                    None => current_token.push(ch),
+                }
+            }
+            '`' if !is_in_single_quotes => {
+                let command = read_backtick_substitution(&mut input_chars);
+                let value = expander.command_output(&command);
+                current_token_quoted = true;
+                push_expansion(
+                    &mut tokens,
+                    &mut current_token,
+                    &mut glob_positions,
+                    value.trim_end_matches('\n'),
+                    is_in_double_quotes,
+                );
+            }
+            '~' if current_token.is_empty()
+                && !current_token_quoted
+                && !is_in_single_quotes
+                && !is_in_double_quotes =>
+            {
+                let mut prefix = ch.to_string();
+                while let Some(&next) = input_chars.peek() {
+                    if !(next.is_alphanumeric() || matches!(next, '_' | '-' | '.')) {
+                        break;
+                    }
+                    prefix.push(next);
+                    input_chars.next();
+                }
+                let ends_prefix = input_chars.peek().map_or(true, |&next| {
+                    next == '/' || next.is_whitespace() || is_operator_char(next)
+                });
+                if ends_prefix {
+                    current_token = expand_tilde(&prefix);
+                    current_token_quoted = current_token != prefix;
+                } else {
+                    current_token = prefix;
+                }
+            }
             ch if ch.is_whitespace() => {
                 if is_in_single_quotes || is_in_double_quotes {
                     current_token.push(ch);
                 } else if !current_token.is_empty() {
-                    tokens.push(current_token.clone());
-                    current_token.clear();
+                    let word = std::mem::take(&mut current_token);
+                    push_word(&mut tokens, word, &mut glob_positions);
+                    current_token_quoted = false;
+                }
+            }
+            ch if is_operator_char(ch) && !is_in_single_quotes && !is_in_double_quotes => {
+                let mut op = read_operator(ch, &mut input_chars);
+                let is_fd_prefix = !current_token_quoted
+                    && !current_token.is_empty()
+                    && current_token.chars().all(|c| c.is_ascii_digit())
+                    && (op.starts_with('>') || op.starts_with('<'));
+                let word = std::mem::take(&mut current_token);
+                if is_fd_prefix {
+                    op.insert_str(0, &word);
+                } else if !word.is_empty() {
+                    push_word(&mut tokens, word, &mut glob_positions);
                 }
+                current_token_quoted = false;
+                tokens.push(Token::Operator(op));
             }
             _ => {
+                let is_quoted = is_in_single_quotes || is_in_double_quotes;
+                if !is_quoted && (GLOB_CHARS.contains(&ch) || ch == ']') {
+                    glob_positions.push(current_token.len());
+                }
                 current_token.push(ch);
             }
         }
     }
     if !current_token.is_empty() {
-        tokens.push(current_token);
+        push_word(&mut tokens, current_token, &mut glob_positions);
+    }
+
+    tokens
+}
+
+// Words with unquoted glob characters become patterns, with quoted ones escaped.
+fn push_word(tokens: &mut Vec<Token>, word: String, glob_positions: &mut Vec<usize>) {
+    let is_pattern = glob_positions
+        .iter()
+        .any(|&index| word[index..].starts_with(GLOB_CHARS));
+    if !is_pattern {
+        glob_positions.clear();
+        tokens.push(Token::Word(word));
+        return;
+    }
+
+    let mut pattern = String::new();
+    for (index, ch) in word.char_indices() {
+        let is_special = GLOB_CHARS.contains(&ch) || ch == ']' || ch == '\\';
+        if is_special && !glob_positions.contains(&index) {
+            pattern.push('\\');
+        }
+        pattern.push(ch);
+    }
+    glob_positions.clear();
+    tokens.push(Token::Pattern(pattern));
+}
+
+fn push_expansion(
+    tokens: &mut Vec<Token>,
+    current_token: &mut String,
+    glob_positions: &mut Vec<usize>,
+    value: &str,
+    is_in_double_quotes: bool,
+) {
+    if is_in_double_quotes {
+        current_token.push_str(value);
+        return;
+    }
+    for value_char in value.chars() {
+        if !value_char.is_whitespace() {
+            current_token.push(value_char);
+        } else if !current_token.is_empty() {
+            push_word(tokens, std::mem::take(current_token), glob_positions);
+        }
+    }
+}
+
+fn read_command_substitution(input_chars: &mut Peekable<std::str::Chars<'_>>) -> String {
+    let mut command = String::new();
+    let mut depth = 1;
+    let mut is_in_single_quotes = false;
+    let mut is_in_double_quotes = false;
+    while let Some(ch) = input_chars.next() {
+        match ch {
+            '\\' if !is_in_single_quotes => {
+                command.push(ch);
+                if let Some(next_char) = input_chars.next() {
+                    command.push(next_char);
+                }
+                continue;
+            }
+            '\'' if !is_in_double_quotes => is_in_single_quotes = !is_in_single_quotes,
+            '"' if !is_in_single_quotes => is_in_double_quotes = !is_in_double_quotes,
+            '(' if !is_in_single_quotes && !is_in_double_quotes => depth += 1,
+            ')' if !is_in_single_quotes && !is_in_double_quotes => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            _ => {}
+        }
+        command.push(ch);
+    }
+    command
+}
+
+fn read_backtick_substitution(input_chars: &mut Peekable<std::str::Chars<'_>>) -> String {
+    let mut command = String::new();
+    while let Some(ch) = input_chars.next() {
+        match ch {
+            '`' => break,
+            '\\' => match input_chars.peek() {
+                Some(&next_char @ ('$' | '`' | '\\')) => {
+                    command.push(next_char);
+                    input_chars.next();
+                }
+                _ => command.push(ch),
+            },
+            _ => command.push(ch),
+        }
+    }
+    command
+}
+
+fn read_variable(
+    input_chars: &mut Peekable<std::str::Chars<'_>>,
+    expander: &dyn Expand,
+) -> Option<String> {
+    let mut name = String::new();
+    match input_chars.peek() {
+        Some('{') => {
+            input_chars.next();
+            for ch in input_chars.by_ref() {
+                if ch == '}' {
+                    break;
+                }
+                name.push(ch);
+            }
+        }
+        Some(&ch) if ch == '_' || ch.is_ascii_alphabetic() => {
+            while let Some(&ch) = input_chars.peek() {
+                if ch != '_' && !ch.is_ascii_alphanumeric() {
+                    break;
+                }
+                name.push(ch);
+                input_chars.next();
+            }
+        }
+        Some(&ch) if SPECIAL_PARAMETERS.contains(&ch) => {
+            input_chars.next();
+            name.push(ch);
+        }
+        _ => return None,
+    }
+
+    let mut name_chars = name.chars();
+    if let (Some(ch), None) = (name_chars.next(), name_chars.next()) {
+        if SPECIAL_PARAMETERS.contains(&ch) {
+            return Some(expander.special_parameter(ch).unwrap_or_default());
+        }
+    }
+    Some(env::var(name).unwrap_or_default())
+}
+
+// Like double-quoted text: parameters and substitutions expand, but nothing is split.
+pub fn expand_text(input: &str, expander: &mut dyn Expand) -> String {
+    let mut text = String::new();
+    let mut input_chars = input.chars().peekable();
+    while let Some(ch) = input_chars.next() {
+        match ch {
+            '$' if input_chars.peek() == Some(&'(') => {
+                input_chars.next();
+                let command = read_command_substitution(&mut input_chars);
+                text.push_str(expander.command_output(&command).trim_end_matches('\n'));
+            }
+            '$' => match read_variable(&mut input_chars, expander) {
+                Some(value) => text.push_str(&value),
+                None => text.push(ch),
+            },
+            '`' => {
+                let command = read_backtick_substitution(&mut input_chars);
+                text.push_str(expander.command_output(&command).trim_end_matches('\n'));
+            }
+            '\\' => match input_chars.peek() {
+                Some(&next @ ('$' | '`' | '\\')) => {
+                    text.push(next);
+                    input_chars.next();
+                }
+                _ => text.push(ch),
+            },
+            _ => text.push(ch),
+        }
     }
+    text
+}
 
-    let redirect = parse_redirect(&mut tokens)?;
-    Ok((tokens, redirect))
+fn is_operator_char(ch: char) -> bool {
+    OPERATORS.iter().any(|op| op.starts_with(ch))
+}
+
+fn read_operator(first: char, input_chars: &mut Peekable<std::str::Chars<'_>>) -> String {
+    let mut op = first.to_string();
+    if let Some(&next_char) = input_chars.peek() {
+        let candidate = format!("{}{}", first, next_char);
+        if OPERATORS.contains(&candidate.as_str()) {
+            input_chars.next();
+            op = candidate;
+        }
+    }
+    op
+}
+
+pub fn needs_continuation(input: &str) -> bool {
+    let mut input_chars = input.chars().peekable();
+    let mut contexts: Vec<char> = Vec::new();
+    let mut previous = ' ';
+    while let Some(ch) = input_chars.next() {
+        match (contexts.last().copied(), ch) {
+            (Some('\''), '\'') | (Some('`'), '`') | (Some('"'), '"') | (Some('('), ')') => {
+                contexts.pop();
+            }
+            (Some('\'' | '`'), _) => {}
+            (None | Some('('), '#') if previous.is_whitespace() => {
+                skip_comment(&mut input_chars);
+            }
+            (_, '\\') => match input_chars.next() {
+                None => return true,
+                Some('\n') if input_chars.peek().is_none() => return true,
+                Some(_) => {}
+            },
+            (Some('"'), '$') | (None | Some('('), '$') if input_chars.peek() == Some(&'(') => {
+                input_chars.next();
+                contexts.push('(');
+            }
+            (Some('"'), '`') | (None | Some('('), '`' | '"' | '\'') => contexts.push(ch),
+            (Some('('), '(') => contexts.push(ch),
+            _ => {}
+        }
+        previous = ch;
+    }
+    !contexts.is_empty()
 }
 
 fn handle_escape(
@@ -67,7 +623,10 @@ fn handle_escape(
     if let Some(&next_char) = input_chars.peek() {
         if is_in_double_quotes {
             match next_char {
-                '"' | '$' | '\\' | '`' | '\n' => {
+                '\n' => {
+                    input_chars.next();
+                }
+                '"' | '$' | '\\' | '`' => {
                     current_token.push(next_char);
                     input_chars.next();
                 }
@@ -78,7 +637,9 @@ fn handle_escape(
                 }
             }
         } else {
-            current_token.push(next_char);
+            if next_char != '\n' {
+                current_token.push(next_char);
+            }
             input_chars.next();
         }
     } else {
@@ -86,77 +647,100 @@ fn handle_escape(
     }
 }
 
-fn parse_redirect(tokens: &mut Vec<String>) -> Result<Option<Redirect>, String> {
-    if tokens.len() < 2 {
+fn parse_redirect(op_token: &str, target: Option<Token>) -> Result<Option<Redirect>, String> {
+    let Some((fd_part, op_part)) = redirect_parts(op_token) else {
         return Ok(None);
-    }
+    };
+    let target = match target {
+        Some(Token::Word(target)) => target,
+        Some(Token::Pattern(pattern)) => unescape(&pattern),
+        _ => return Err("missing file name for redirect".to_string()),
+    };
+
+    let redirect_type = match op_part {
+        ">>" => RedirectType::APPEND,
+        ">" => RedirectType::CREATE,
+        "<" => RedirectType::READ,
+        _ => RedirectType::DUP(
+            target
+                .parse::<u32>()
+                .map_err(|_| format!("invalid file descriptor: {}", target))?,
+        ),
+    };
+    let fd = if fd_part.is_empty() {
+        match redirect_type {
+            RedirectType::READ => 0,
+            _ => 1,
+        }
+    } else {
+        fd_part
+            .parse::<u32>()
+            .map_err(|_| format!("invalid file descriptor: {}", fd_part))?
+    };
 
-    let op_token = tokens[tokens.len() - 2].as_str();
+    Ok(Some(Redirect {
+        fd,
+        target,
+        redirect_type,
+    }))
+}
+
+fn redirect_parts(op_token: &str) -> Option<(&str, &str)> {
     let split_idx = op_token
         .find(|c: char| !c.is_ascii_digit())
         .unwrap_or(op_token.len());
     let (fd_part, op_part) = op_token.split_at(split_idx);
 
-    let redirect_type_optional = match op_part {
-        ">>" => Some(RedirectType::APPEND),
-        ">" => Some(RedirectType::CREATE),
+    match op_part {
+        ">>" | ">" | "<" | ">&" => Some((fd_part, op_part)),
         _ => None,
-    };
-
-    let fd_optional = match redirect_type_optional {
-        Some(_) => {
-            if fd_part.is_empty() {
-                Some(1)
-            } else {
-                Some(
-                    fd_part
-                        .parse::<u32>()
-                        .map_err(|_| format!("invalid file descriptor: {}", fd_part))?,
-                )
-            }
-        }
-        None => None,
-    };
-
-    if let (Some(fd), Some(redirect_type)) = (fd_optional, redirect_type_optional) {
-        let filename = tokens
-            .pop()
-            .ok_or_else(|| "missing file name for redirect".to_string())?;
-        tokens.pop();
-        Ok(Some(Redirect {
-            fd,
-            target: filename,
-            redirect_type,
-        }))
-    } else {
-        Ok(None)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::TempDir;
+
+    fn no_substitution(_command: &str) -> String {
+        String::new()
+    }
+
+    fn item(connector: Option<Connector>, pipeline: &str, background: bool) -> ListItem {
+        ListItem {
+            connector,
+            pipeline: pipeline.to_string(),
+            background,
+        }
+    }
+
+    fn single_command(input: &str) -> (Vec<String>, Vec<Redirect>) {
+        let mut commands = tokenize(input, &mut no_substitution).unwrap();
+        assert_eq!(1, commands.len());
+        let command = commands.remove(0);
+        (command.args, command.redirects)
+    }
 
     #[test]
     fn tokenizes_basic_command() {
-        let (tokens, redirect) = tokenize("echo hello world").unwrap();
+        let (tokens, redirects) = single_command("echo hello world");
         assert_eq!(tokens, vec!["echo", "hello", "world"]);
-        assert!(redirect.is_none());
+        assert!(redirects.is_empty());
     }
 
     #[test]
     fn preserves_whitespace_inside_quotes() {
-        let (tokens, redirect) = tokenize("echo \"hello world\"").unwrap();
+        let (tokens, redirects) = single_command("echo \"hello world\"");
         assert_eq!(tokens, vec!["echo", "hello world"]);
-        assert!(redirect.is_none());
+        assert!(redirects.is_empty());
     }
 
     #[test]
     fn extracts_redirect_information() {
-        let (tokens, redirect) = tokenize("echo hi > out.txt").unwrap();
+        let (tokens, mut redirects) = single_command("echo hi > out.txt");
         assert_eq!(tokens, vec!["echo", "hi"]);
 
-        let redirect = redirect.expect("expected redirect");
+        let redirect = redirects.pop().expect("expected redirect");
         assert_eq!(redirect.fd, 1);
         assert_eq!(redirect.target, "out.txt");
         assert!(matches!(redirect.redirect_type, RedirectType::CREATE));
@@ -164,8 +748,403 @@ mod tests {
 
     #[test]
     fn handles_escape_sequences() {
-        let (tokens, redirect) = tokenize(r"echo foo\ bar").unwrap();
+        let (tokens, redirects) = single_command(r"echo foo\ bar");
         assert_eq!(tokens, vec!["echo", "foo bar"]);
-        assert!(redirect.is_none());
+        assert!(redirects.is_empty());
+    }
+
+    fn word(text: &str) -> Token {
+        Token::Word(text.to_string())
+    }
+
+    fn op(text: &str) -> Token {
+        Token::Operator(text.to_string())
+    }
+
+    #[test]
+    fn splits_operators_without_whitespace() {
+        assert_eq!(
+            lex("a|b", &mut no_substitution),
+            vec![word("a"), op("|"), word("b")]
+        );
+        assert_eq!(
+            lex("a&&b", &mut no_substitution),
+            vec![word("a"), op("&&"), word("b")]
+        );
+        assert_eq!(
+            lex("a;b", &mut no_substitution),
+            vec![word("a"), op(";"), word("b")]
+        );
+        assert_eq!(
+            lex("a>b", &mut no_substitution),
+            vec![word("a"), op(">"), word("b")]
+        );
+    }
+
+    #[test]
+    fn keeps_quoted_operators_as_words() {
+        assert_eq!(
+            lex("echo 'a|b' \";\"", &mut no_substitution),
+            vec![word("echo"), word("a|b"), word(";")]
+        );
+    }
+
+    #[test]
+    fn attaches_fd_prefix_to_redirect_operator() {
+        assert_eq!(
+            lex("cmd 2>>err", &mut no_substitution),
+            vec![word("cmd"), op("2>>"), word("err")]
+        );
+
+        let (tokens, mut redirects) = single_command("ls nope 2>err.txt");
+        assert_eq!(tokens, vec!["ls", "nope"]);
+        let redirect = redirects.pop().expect("expected redirect");
+        assert_eq!(redirect.fd, 2);
+        assert_eq!(redirect.target, "err.txt");
+        assert!(matches!(redirect.redirect_type, RedirectType::CREATE));
+    }
+
+    #[test]
+    fn parses_fd_duplication() {
+        assert_eq!(
+            lex("cmd 2>&1", &mut no_substitution),
+            vec![word("cmd"), op("2>&"), word("1")]
+        );
+
+        let (tokens, redirects) = single_command("ls nope > out.txt 2>&1");
+        assert_eq!(tokens, vec!["ls", "nope"]);
+        assert_eq!(redirects.len(), 2);
+        assert_eq!(redirects[1].fd, 2);
+        assert!(matches!(redirects[1].redirect_type, RedirectType::DUP(1)));
+
+        let (_, redirects) = single_command("echo hi >&2");
+        assert_eq!(redirects[0].fd, 1);
+        assert!(matches!(redirects[0].redirect_type, RedirectType::DUP(2)));
+
+        assert_eq!(
+            tokenize("echo hi 2>&out", &mut no_substitution).unwrap_err(),
+            "invalid file descriptor: out"
+        );
+    }
+
+    #[test]
+    fn only_unquoted_glob_characters_make_patterns() {
+        assert_eq!(
+            lex("echo *.txt '*' \"a*\"b? x]", &mut no_substitution),
+            vec![
+                word("echo"),
+                Token::Pattern("*.txt".to_string()),
+                word("*"),
+                Token::Pattern("a\\*b?".to_string()),
+                word("x]"),
+            ]
+        );
+
+        let root = TempDir::new("parser_glob");
+        std::fs::write(root.join("one.log"), "").unwrap();
+        std::fs::write(root.join("two.log"), "").unwrap();
+        let (tokens, _) = single_command(&format!("ls {0}/*.log '{0}/*.log'", root.display()));
+
+        assert_eq!(
+            tokens,
+            vec![
+                "ls".to_string(),
+                format!("{}/one.log", root.display()),
+                format!("{}/two.log", root.display()),
+                format!("{}/*.log", root.display()),
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_lists_at_top_level_connectors() {
+        let items =
+            split_list("mkdir a && cd a || echo 'x && y' \"$(true || false)\" | cat").unwrap();
+        assert_eq!(
+            items,
+            vec![
+                item(None, "mkdir a", false),
+                item(Some(Connector::And), "cd a", false),
+                item(
+                    Some(Connector::Or),
+                    "echo 'x && y' \"$(true || false)\" | cat",
+                    false
+                ),
+            ]
+        );
+
+        assert_eq!(
+            split_list("&& ls").unwrap_err(),
+            "syntax error near unexpected token `&&'"
+        );
+        assert_eq!(
+            split_list("ls ||").unwrap_err(),
+            "syntax error: unexpected end of file"
+        );
+    }
+
+    #[test]
+    fn splits_sequential_commands_at_unquoted_semicolons() {
+        let items = split_list("echo one; echo 'a;b' c\\; && pwd;").unwrap();
+        assert_eq!(
+            items,
+            vec![
+                item(None, "echo one", false),
+                item(None, "echo 'a;b' c\\;", false),
+                item(Some(Connector::And), "pwd", false),
+            ]
+        );
+
+        assert_eq!(
+            split_list("; ls").unwrap_err(),
+            "syntax error near unexpected token `;'"
+        );
+    }
+
+    #[test]
+    fn marks_background_pipelines() {
+        let items = split_list("sleep 5 & ls 2>&1 >&2 && echo 'a & b' &").unwrap();
+        assert_eq!(
+            items,
+            vec![
+                item(None, "sleep 5", true),
+                item(None, "ls 2>&1 >&2", false),
+                item(Some(Connector::And), "echo 'a & b'", true),
+            ]
+        );
+
+        assert_eq!(
+            split_list("& ls").unwrap_err(),
+            "syntax error near unexpected token `&'"
+        );
+    }
+
+    #[test]
+    fn keeps_newlines_inside_quotes() {
+        let (tokens, _) = single_command("cd \"my dir\nname\"");
+        assert_eq!(tokens, vec!["cd", "my dir\nname"]);
+    }
+
+    #[test]
+    fn backslash_newline_joins_lines() {
+        let (tokens, _) = single_command("echo foo\\\nbar \"a\\\nb\"");
+        assert_eq!(tokens, vec!["echo", "foobar", "ab"]);
+    }
+
+    #[test]
+    fn detects_input_needing_continuation() {
+        assert!(needs_continuation("cd \"my dir\n"));
+        assert!(needs_continuation("echo 'it\n"));
+        assert!(needs_continuation("echo foo \\\n"));
+        assert!(!needs_continuation("echo \"done\"\n"));
+        assert!(!needs_continuation("echo \"it's\"\n"));
+        assert!(!needs_continuation("echo done # it's fine\n"));
+        assert!(needs_continuation("echo $(\n"));
+        assert!(needs_continuation(
+            "echo $(\n# comment with )\necho \"$(date\n"
+        ));
+        assert!(!needs_continuation("echo $(\necho inner # (\n)\n"));
+    }
+
+    #[test]
+    fn strips_comments_at_top_level_and_inside_substitutions() {
+        let items =
+            split_list("echo \"$(\n  # pick (one)\n  echo a\n)\" # trailing\necho b#c\n").unwrap();
+        assert_eq!(
+            items,
+            vec![
+                item(None, "echo \"$(\n  \n  echo a\n)\"", false),
+                item(None, "echo b#c", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_pipeline_into_commands() {
+        let commands = tokenize("ls -l | grep foo > out.txt|wc -l", &mut no_substitution).unwrap();
+        let args: Vec<Vec<String>> = commands.iter().map(|c| c.args.clone()).collect();
+        assert_eq!(
+            args,
+            vec![vec!["ls", "-l"], vec!["grep", "foo"], vec!["wc", "-l"]]
+        );
+        assert!(commands[0].redirects.is_empty());
+        assert_eq!("out.txt", commands[1].redirects[0].target);
+
+        let commands = tokenize("ls 2> err |& wc", &mut no_substitution).unwrap();
+        let redirect = &commands[0].redirects[1];
+        assert_eq!(2, redirect.fd);
+        assert!(matches!(redirect.redirect_type, RedirectType::DUP(1)));
+        assert!(commands[1].redirects.is_empty());
+        assert_eq!(
+            split_list("ls |& wc &").unwrap(),
+            vec![item(None, "ls |& wc", true)]
+        );
+    }
+
+    #[test]
+    fn splits_stages_only_at_top_level_pipes() {
+        assert_eq!(
+            split_stages("echo 'a|b' \"$(ls | wc)\" \\| x | grep y|cat"),
+            vec!["echo 'a|b' \"$(ls | wc)\" \\| x ", " grep y", "cat"]
+        );
+        assert_eq!(split_stages("ls x |& wc"), vec!["ls x  2>&1 ", " wc"]);
+    }
+
+    #[test]
+    fn strips_standalone_negation_words() {
+        assert_eq!((true, "true"), strip_negation("! true"));
+        assert_eq!((false, "grep -q x f"), strip_negation("! ! grep -q x f"));
+        assert_eq!((false, "!true"), strip_negation("!true"));
+        assert_eq!((false, "echo !"), strip_negation("echo !"));
+    }
+
+    #[test]
+    fn rejects_empty_pipeline_stage() {
+        assert!(tokenize("ls | | wc", &mut no_substitution).is_err());
+        assert!(tokenize("| wc", &mut no_substitution).is_err());
+        assert!(tokenize("ls |", &mut no_substitution).is_err());
+        assert_eq!(
+            1,
+            tokenize("echo 'a | b'", &mut no_substitution)
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn expands_variables_outside_single_quotes() {
+        env::set_var("TOKENIZE_TEST_VAR", "two  words");
+        let (tokens, _) = single_command(
+            "echo $TOKENIZE_TEST_VAR \"${TOKENIZE_TEST_VAR}!\" '$TOKENIZE_TEST_VAR' $UNDEFINED_TEST_VAR end",
+        );
+        env::remove_var("TOKENIZE_TEST_VAR");
+
+        assert_eq!(
+            tokens,
+            vec![
+                "echo",
+                "two",
+                "words",
+                "two  words!",
+                "$TOKENIZE_TEST_VAR",
+                "end"
+            ]
+        );
+    }
+
+    #[test]
+    fn expands_unquoted_leading_tilde() {
+        let home = env::var("HOME").unwrap_or_default();
+        let root_home = crate::utils::home_dir_of("root").unwrap();
+        let (tokens, _) =
+            single_command("ls ~ ~/src ~root/x '~' \"~/q\" a~b ~no_such_user_here ~root'x'");
+
+        assert_eq!(
+            tokens,
+            vec![
+                "ls".to_string(),
+                home.clone(),
+                format!("{}/src", home),
+                format!("{}/x", root_home),
+                "~".to_string(),
+                "~/q".to_string(),
+                "a~b".to_string(),
+                "~no_such_user_here".to_string(),
+                "~rootx".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_lone_dollar_literal() {
+        let (tokens, _) = single_command("echo $ cost=5$ \\$HOME");
+        assert_eq!(tokens, vec!["echo", "$", "cost=5$", "$HOME"]);
+    }
+
+    #[test]
+    fn substitutes_commands_and_strips_trailing_newlines() {
+        let mut substitute = |command: &str| format!("[{}] out\n\n", command);
+        let commands = tokenize(
+            "echo $(date +%F) \"$(echo \")\")\" `echo \\`x\\``",
+            &mut substitute,
+        )
+        .unwrap();
+
+        assert_eq!(
+            commands[0].args,
+            vec![
+                "echo",
+                "[date",
+                "+%F]",
+                "out",
+                "[echo \")\"] out",
+                "[echo",
+                "`x`]",
+                "out"
+            ]
+        );
+    }
+
+    #[test]
+    fn passes_nested_substitutions_through_whole() {
+        let mut seen = Vec::new();
+        let mut substitute = |command: &str| {
+            seen.push(command.to_string());
+            String::new()
+        };
+        tokenize("echo $(echo $(echo hi) (x))", &mut substitute).unwrap();
+
+        assert_eq!(seen, vec!["echo $(echo hi) (x)"]);
+    }
+
+    struct LastStatus(i32);
+
+    impl Expand for LastStatus {
+        fn command_output(&mut self, _command: &str) -> String {
+            String::new()
+        }
+
+        fn special_parameter(&self, name: char) -> Option<String> {
+            (name == '?').then(|| self.0.to_string())
+        }
+    }
+
+    #[test]
+    fn expands_last_status() {
+        let commands = tokenize("echo $? ${?} '$?' \"[$?]\"", &mut LastStatus(3)).unwrap();
+        assert_eq!(commands[0].args, vec!["echo", "3", "3", "$?", "[3]"]);
+    }
+
+    #[test]
+    fn extracts_input_redirect() {
+        let (tokens, mut redirects) = single_command("sort -r <in.txt");
+        assert_eq!(tokens, vec!["sort", "-r"]);
+
+        let redirect = redirects.pop().expect("expected redirect");
+        assert_eq!(redirect.fd, 0);
+        assert_eq!(redirect.target, "in.txt");
+        assert!(matches!(redirect.redirect_type, RedirectType::READ));
+    }
+
+    #[test]
+    fn collects_redirects_from_any_position() {
+        let (tokens, redirects) = single_command("echo > out.txt hi 2> err.txt there >>log.txt");
+        assert_eq!(tokens, vec!["echo", "hi", "there"]);
+
+        let summary: Vec<(u32, &str)> = redirects
+            .iter()
+            .map(|redirect| (redirect.fd, redirect.target.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![(1, "out.txt"), (2, "err.txt"), (1, "log.txt")]
+        );
+        assert!(matches!(redirects[2].redirect_type, RedirectType::APPEND));
+    }
+
+    #[test]
+    fn rejects_redirect_without_target() {
+        assert!(tokenize("echo hi >", &mut no_substitution).is_err());
+        assert!(tokenize("echo hi > | cat", &mut no_substitution).is_err());
     }
 }
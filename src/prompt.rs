@@ -0,0 +1,133 @@
+use std::env;
+use std::ffi::CStr;
+
+pub const DEFAULT_PS1: &str = "$ ";
+
+// Substituted text is backslash-escaped so the later $ and ` expansion leaves it alone.
+pub fn render_prompt(ps1: &str, job_count: usize) -> String {
+    let mut prompt = String::new();
+    let mut ps1_chars = ps1.chars();
+    while let Some(ch) = ps1_chars.next() {
+        if ch != '\\' {
+            prompt.push(ch);
+            continue;
+        }
+        match ps1_chars.next() {
+            Some('j') => push_escaped(&mut prompt, &job_count.to_string()),
+            Some('w') => push_escaped(&mut prompt, &working_dir()),
+            Some('W') => {
+                let dir = working_dir();
+                let base = match dir.rsplit_once('/') {
+                    Some((_, base)) if !base.is_empty() => base.to_string(),
+                    _ => dir,
+                };
+                push_escaped(&mut prompt, &base);
+            }
+            Some('u') => push_escaped(&mut prompt, &user_name()),
+            Some('h') => push_escaped(
+                &mut prompt,
+                host_name().split('.').next().unwrap_or_default(),
+            ),
+            Some('H') => push_escaped(&mut prompt, &host_name()),
+            Some('$') => {
+                let marker = if unsafe { libc::geteuid() } == 0 {
+                    "#"
+                } else {
+                    "$"
+                };
+                push_escaped(&mut prompt, marker);
+            }
+            Some('\\') => push_escaped(&mut prompt, "\\"),
+            Some(other) => {
+                prompt.push('\\');
+                prompt.push(other);
+            }
+            None => prompt.push('\\'),
+        }
+    }
+    prompt
+}
+
+fn push_escaped(prompt: &mut String, text: &str) {
+    for ch in text.chars() {
+        if matches!(ch, '$' | '`' | '\\') {
+            prompt.push('\\');
+        }
+        prompt.push(ch);
+    }
+}
+
+fn working_dir() -> String {
+    let Ok(dir) = env::current_dir() else {
+        return String::new();
+    };
+    let home = env::var("HOME").unwrap_or_default();
+    abbreviate_home(&dir.to_string_lossy(), &home)
+}
+
+fn abbreviate_home(dir: &str, home: &str) -> String {
+    let home = home.trim_end_matches('/');
+    match dir.strip_prefix(home) {
+        Some(rest) if !home.is_empty() && (rest.is_empty() || rest.starts_with('/')) => {
+            format!("~{}", rest)
+        }
+        _ => dir.to_string(),
+    }
+}
+
+fn user_name() -> String {
+    if let Ok(user) = env::var("USER") {
+        return user;
+    }
+    let passwd = unsafe { libc::getpwuid(libc::geteuid()) };
+    if passwd.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr((*passwd).pw_name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn host_name() -> String {
+    let mut buffer = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr(), buffer.len()) } != 0 {
+        return String::new();
+    }
+    buffer[buffer.len() - 1] = 0;
+    unsafe { CStr::from_ptr(buffer.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_job_count() {
+        assert_eq!("[2] $ ", render_prompt("[\\j] $ ", 2));
+        assert_eq!("jobs=0 \\q$ ", render_prompt("jobs=\\j \\q$ ", 0));
+        assert_eq!("$ ", render_prompt(DEFAULT_PS1, 5));
+    }
+
+    #[test]
+    fn renders_user_host_and_directory_escapes() {
+        let prompt = render_prompt("\\u@\\h:\\W\\$ \\\\", 0);
+        let marker = if unsafe { libc::geteuid() } == 0 {
+            "# \\\\"
+        } else {
+            "\\$ \\\\"
+        };
+
+        assert!(prompt.ends_with(marker));
+        assert!(prompt.starts_with(&format!(
+            "{}@{}:",
+            user_name(),
+            host_name().split('.').next().unwrap()
+        )));
+        assert_eq!("~/src", abbreviate_home("/home/me/src", "/home/me/"));
+        assert_eq!("~", abbreviate_home("/home/me", "/home/me"));
+        assert_eq!("/home/meow", abbreviate_home("/home/meow", "/home/me"));
+        assert_eq!("/tmp", abbreviate_home("/tmp", ""));
+    }
+}
@@ -1,116 +1,661 @@
-use std::fs::File;
-use std::io::{self, Write};
-use std::process::{self, Stdio};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, ExitStatus, Stdio};
+use std::time::Instant;
 
-use crate::builtins::{BuiltinFlow, Builtins};
-use crate::io_helpers::{get_write_output, OutputSink};
-use crate::parser::tokenize;
+use crate::builtins::{BuiltinFlow, BuiltinFn, Builtins};
+use crate::completion::complete_line;
+use crate::glob;
+use crate::io_helpers::{create_pipe, duplicate_std_fd, get_write_output, OutputSink};
+use crate::jobs::JobTable;
+use crate::line_editor;
+use crate::parser::{
+    expand_text, needs_continuation, split_list, split_stages, strip_negation, tokenize, Command,
+    Connector, Expand, RedirectType,
+};
+use crate::prompt::{render_prompt, DEFAULT_PS1};
+use crate::signals;
+use crate::timing::{format_report, CpuTimes, DEFAULT_TIMEFORMAT};
 use crate::utils::{find_executable, write_line};
 
 pub struct Shell {
-    builtins: Builtins,
+    pub(crate) builtins: Builtins,
+    pub(crate) recent_dirs: Vec<PathBuf>,
+    pub(crate) history: Vec<String>,
+    pub(crate) aliases: HashMap<String, String>,
+    history_file: Option<PathBuf>,
+    history_saved: usize,
+    pub(crate) last_status: i32,
+    pub(crate) pipefail: bool,
+    pub(crate) jobs: JobTable,
+    pub(crate) source_depth: usize,
+    exit_requested: bool,
+    return_requested: bool,
+    captured_stdout: Option<File>,
 }
 
 impl Shell {
     pub fn new() -> Self {
         Shell {
             builtins: Builtins::new(),
+            recent_dirs: Vec::new(),
+            history: Vec::new(),
+            aliases: HashMap::new(),
+            history_file: None,
+            history_saved: 0,
+            last_status: 0,
+            pipefail: false,
+            jobs: JobTable::default(),
+            source_depth: 0,
+            exit_requested: false,
+            return_requested: false,
+            captured_stdout: None,
         }
     }
 
     pub fn run(&mut self) -> io::Result<()> {
+        signals::install_interrupt_handler()?;
+        signals::install_child_handler()?;
+        if let Some(path) = history_file_path() {
+            self.load_history(path);
+        }
         loop {
-            print!("$ ");
+            for finished in self.reap_jobs() {
+                eprintln!("{}", finished);
+            }
+            let prompt = self.prompt();
+            print!("{}", prompt);
             io::stdout().flush()?;
 
             let mut command = String::new();
-            if io::stdin().read_line(&mut command)? == 0 {
+            if self.read_input(&mut command)? == 0 {
                 continue;
             }
+            while needs_continuation(&command) {
+                print!("> ");
+                io::stdout().flush()?;
+                if self.read_input(&mut command)? == 0 {
+                    break;
+                }
+            }
+
+            self.record_history(command.trim_end_matches('\n'));
+            // Ctrl-C at the prompt was already handled by the line editor.
+            signals::take_interrupt();
+            if self.execute_line(&command)?.exit_requested {
+                self.exit();
+            }
+            // A foreground child killed by Ctrl-C leaves the cursor after ^C.
+            if signals::take_interrupt() {
+                println!();
+            }
+        }
+    }
+
+    // The job table is only scanned once SIGCHLD says some child has exited. Jobs are
+    // reaped with try_wait on their own pids, so this never steals a foreground wait.
+    fn reap_jobs(&mut self) -> Vec<String> {
+        if !signals::take_child_exit() {
+            return Vec::new();
+        }
+        self.jobs.reap()
+    }
+
+    fn read_input(&self, command: &mut String) -> io::Result<usize> {
+        let builtins = &self.builtins;
+        line_editor::read_line(command, &mut |line| complete_line(line, builtins))
+    }
+
+    fn record_history(&mut self, entry: &str) {
+        if entry.trim().is_empty() {
+            return;
+        }
+        let ignored = env::var("HISTIGNORE").is_ok_and(|patterns| {
+            patterns
+                .split(':')
+                .any(|pattern| !pattern.is_empty() && glob::matches(pattern, entry))
+        });
+        if !ignored {
+            self.history.push(entry.to_string());
+        }
+    }
+
+    fn load_history(&mut self, path: PathBuf) {
+        if let Ok(contents) = fs::read_to_string(&path) {
+            self.history = contents.lines().map(String::from).collect();
+        }
+        self.history_saved = self.history.len();
+        self.history_file = Some(path);
+    }
 
-            let command = command.trim();
-            if command.is_empty() {
+    pub(crate) fn clear_history(&mut self) {
+        self.history.clear();
+        self.history_saved = 0;
+    }
+
+    fn save_history(&mut self) -> io::Result<()> {
+        let Some(path) = &self.history_file else {
+            return Ok(());
+        };
+        let filename = path.to_string_lossy();
+        let mut file = get_write_output(&filename, RedirectType::APPEND)?;
+        for entry in self.history.iter().skip(self.history_saved) {
+            writeln!(file, "{}", entry)?;
+        }
+        self.history_saved = self.history.len();
+
+        let limit = env::var("HISTSIZE")
+            .ok()
+            .and_then(|size| size.parse::<usize>().ok())
+            .unwrap_or(HISTORY_SIZE);
+        let contents = fs::read_to_string(path)?;
+        let lines: Vec<&str> = contents.lines().collect();
+        if lines.len() > limit {
+            let kept: String = lines[lines.len() - limit..]
+                .iter()
+                .map(|line| format!("{}\n", line))
+                .collect();
+            fs::write(path, kept)?;
+        }
+        Ok(())
+    }
+
+    pub fn load_startup_files(&mut self, login: bool) {
+        if let Ok(home) = env::var("HOME") {
+            self.load_startup_files_from(Path::new(&home), login);
+        }
+    }
+
+    fn load_startup_files_from(&mut self, home: &Path, login: bool) {
+        let candidates: &[&str] = if login {
+            &[".shell_profile", ".profile"]
+        } else {
+            &[".shellrc"]
+        };
+        let Some(path) = candidates
+            .iter()
+            .map(|name| home.join(name))
+            .find(|path| path.is_file())
+        else {
+            return;
+        };
+        if let Err(err) = self.run_script(&path.to_string_lossy()) {
+            eprintln!("{}: {}", path.display(), err);
+        }
+    }
+
+    pub fn run_script(&mut self, path: &str) -> io::Result<()> {
+        if self.source_file(path)?.exit_requested {
+            self.exit();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn source_file(&mut self, path: &str) -> io::Result<CommandResult> {
+        let contents = fs::read_to_string(path)?;
+        let mut command = String::new();
+        for line in contents.lines() {
+            if command.is_empty() && line.trim_start().starts_with('#') {
                 continue;
             }
+            command.push_str(line);
+            command.push('\n');
+            if needs_continuation(&command) {
+                continue;
+            }
+            let result = self.execute_line(&command)?;
+            if result.exit_requested || result.return_requested {
+                return Ok(result);
+            }
+            command.clear();
+        }
+        self.execute_line(&command)
+    }
+
+    fn exit(&mut self) -> ! {
+        if let Err(err) = self.save_history() {
+            eprintln!("history: {}", err);
+        }
+        process::exit(self.last_status);
+    }
+
+    pub fn execute_line(&mut self, command: &str) -> io::Result<CommandResult> {
+        self.run_list(command)?;
+        Ok(CommandResult {
+            status: self.last_status,
+            exit_requested: std::mem::take(&mut self.exit_requested),
+            return_requested: std::mem::take(&mut self.return_requested),
+            output: None,
+        })
+    }
+
+    // Runs $(...) in a forked subshell so nothing it does leaks back into this one.
+    pub fn capture(&mut self, command: &str) -> CommandResult {
+        let (mut reader, writer) = match create_pipe() {
+            Ok(pipe) => pipe,
+            Err(err) => {
+                eprintln!("{}", err);
+                return CommandResult::default();
+            }
+        };
+
+        let previous = self.captured_stdout.replace(writer);
+        let child =
+            self.fork_subshell(|shell| shell.execute_line(command).map(|result| result.status));
+        // Dropping the parent's write end lets the read below see EOF.
+        self.captured_stdout = previous;
+
+        let mut output = Vec::new();
+        let _ = reader.read_to_end(&mut output);
+        let status = child.and_then(wait_for_pid).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            1
+        });
+        CommandResult {
+            status,
+            output: Some(String::from_utf8_lossy(&output).into_owned()),
+            ..CommandResult::default()
+        }
+    }
+
+    fn run_list(&mut self, command: &str) -> io::Result<()> {
+        let command = command.trim();
+        if command.is_empty() {
+            return Ok(());
+        }
+
+        let list = match split_list(command) {
+            Ok(list) => list,
+            Err(message) => {
+                eprintln!("{}", message);
+                self.last_status = 2;
+                return Ok(());
+            }
+        };
 
-            let (parts, redirect) = match tokenize(command) {
-                Ok(result) => result,
+        for item in list {
+            let pipeline = item.pipeline.as_str();
+            if !self.connector_allows(item.connector) {
+                continue;
+            }
+
+            // `!` may come before or after `time`, and an odd number of them inverts the status.
+            let (negated, body) = strip_negation(pipeline);
+            let timed = body
+                .strip_prefix("time")
+                .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
+            let (negated_inside, body) = strip_negation(timed.unwrap_or(body));
+            let negated = negated != negated_inside;
+            let started = (Instant::now(), CpuTimes::now());
+            // An alias may expand to a whole list, so the expansion is split again.
+            let expanded = self.expand_aliases(body);
+            let inner = match split_list(&expanded) {
+                Ok(inner) => inner,
                 Err(message) => {
                     eprintln!("{}", message);
+                    self.last_status = 2;
+                    return Ok(());
+                }
+            };
+            let last = inner.len().saturating_sub(1);
+            for (index, inner_item) in inner.iter().enumerate() {
+                if index > 0 && !self.connector_allows(inner_item.connector) {
                     continue;
                 }
+                let commands = match tokenize(&inner_item.pipeline, self) {
+                    Ok(commands) => commands,
+                    Err(message) => {
+                        eprintln!("{}", message);
+                        self.last_status = 2;
+                        return Ok(());
+                    }
+                };
+                let job_command = if inner.len() == 1 {
+                    pipeline
+                } else {
+                    inner_item.pipeline.as_str()
+                };
+                let background = (inner_item.background || (item.background && index == last))
+                    .then_some(job_command);
+                self.last_status = self.run_pipeline(&commands, background)?;
+                if self.exit_requested || self.return_requested {
+                    break;
+                }
+            }
+
+            if negated && !item.background && !self.exit_requested && !self.return_requested {
+                self.last_status = i32::from(self.last_status == 0);
+            }
+            if timed.is_some() {
+                let format =
+                    env::var("TIMEFORMAT").unwrap_or_else(|_| DEFAULT_TIMEFORMAT.to_string());
+                let cpu = CpuTimes::now().since(started.1);
+                eprintln!("{}", format_report(&format, started.0.elapsed(), cpu));
+            }
+            if self.exit_requested || self.return_requested {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn connector_allows(&self, connector: Option<Connector>) -> bool {
+        match connector {
+            None => true,
+            Some(Connector::And) => self.last_status == 0,
+            Some(Connector::Or) => self.last_status != 0,
+        }
+    }
+
+    fn expand_aliases(&self, pipeline: &str) -> String {
+        split_stages(pipeline)
+            .into_iter()
+            .map(|stage| self.expand_alias(&stage))
+            .collect::<Vec<_>>()
+            .join("|")
+    }
+
+    // Only the command word is replaced; an alias is never expanded again inside its own value.
+    fn expand_alias(&self, stage: &str) -> String {
+        let mut expanded = stage.trim_start().to_string();
+        let mut seen = HashSet::new();
+        loop {
+            let end = expanded
+                .find(|c: char| c.is_whitespace() || "|<>".contains(c))
+                .unwrap_or(expanded.len());
+            let name = &expanded[..end];
+            let Some(value) = self.aliases.get(name) else {
+                break;
+            };
+            if !seen.insert(name.to_string()) {
+                break;
+            }
+            expanded = format!("{}{}", value.trim_start(), &expanded[end..]);
+        }
+        expanded
+    }
+
+    fn run_pipeline(&mut self, commands: &[Command], background: Option<&str>) -> io::Result<i32> {
+        if let ([command], None) = (commands, background) {
+            return self.run_command(command);
+        }
+
+        // Every stage is started before any is waited on, so output streams through the pipes.
+        let mut pids = Vec::new();
+        let mut input = None;
+        let mut statuses = vec![0; commands.len()];
+        for (index, command) in commands.iter().enumerate() {
+            let stage_input = input.take();
+            // Every stage but the last writes into a real pipe so 2>&1 can follow stdout.
+            let stage_stdout = if index == commands.len() - 1 {
+                self.captured_stdout()?
+            } else {
+                let (reader, writer) = create_pipe()?;
+                input = Some(reader);
+                Some(writer)
+            };
+            // A stage that fails to start drops its write end, so the next one sees EOF.
+            let Some(mut stdio) = self.open_redirect(command, stage_stdout) else {
+                statuses[index] = 1;
+                continue;
+            };
+            if stdio.stdin.is_none() {
+                stdio.stdin = stage_input;
+            }
+            let Some(command_name) = command.args.first() else {
+                continue;
             };
 
-            if parts.is_empty() {
+            if let Some(&builtin) = self.builtins.get(command_name) {
+                let next_reader = input.as_ref().map(File::as_raw_fd);
+                // Every stage runs in a subshell, so cd, export or exit can't touch this shell:
+                // exit only ends the stage, and its status becomes the stage's status.
+                let pid = self.fork_subshell(|shell| {
+                    if background.is_some() {
+                        signals::ignore_keyboard_signals();
+                    }
+                    // Commands the stage runs read its input, and holding the next stage's
+                    // read end open would keep this stage from ever seeing EPIPE.
+                    unsafe {
+                        if let Some(fd) = next_reader {
+                            libc::close(fd);
+                        }
+                        if let Some(file) = &stdio.stdin {
+                            libc::dup2(file.as_raw_fd(), libc::STDIN_FILENO);
+                        }
+                    }
+                    shell
+                        .run_builtin(builtin, &command.args, &stdio)
+                        .map(BuiltinFlow::status)
+                })?;
+                pids.push((index, pid));
                 continue;
             }
 
-            let (mut stdout_redirect_file, mut stderr_redirect_file) = match redirect {
-                Some(spec) => match spec.fd {
-                    1 => match get_write_output(&spec.target, spec.redirect_type.clone()) {
-                        Ok(file) => (Some(file), None),
-                        Err(err) => {
-                            eprintln!("failed to open {}: {}", spec.target, err);
-                            continue;
+            if !self.check_command_exists(command_name, stdio.stderr.as_ref())? {
+                statuses[index] = COMMAND_NOT_FOUND;
+                continue;
+            }
+            match self.spawn_external(&command.args, &stdio, background.is_some()) {
+                Ok(child) => pids.push((index, child.id() as libc::pid_t)),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    statuses[index] = COMMAND_NOT_EXECUTABLE;
+                }
+            }
+        }
+
+        if let Some(job_command) = background {
+            let pids: Vec<libc::pid_t> = pids.into_iter().map(|(_, pid)| pid).collect();
+            if !pids.is_empty() {
+                let job = self.jobs.add(pids, job_command);
+                eprintln!("[{}] {}", job.id, job.pid());
+            }
+            return Ok(0);
+        }
+
+        for (index, pid) in pids {
+            statuses[index] = wait_for_pid(pid)?;
+        }
+        // Under pipefail the rightmost failing stage decides instead of the last one.
+        let status = if self.pipefail {
+            statuses.iter().rev().find(|&&status| status != 0)
+        } else {
+            statuses.last()
+        };
+        Ok(status.copied().unwrap_or(0))
+    }
+
+    fn run_command(&mut self, command: &Command) -> io::Result<i32> {
+        let Some(stdio) = self.open_redirect(command, self.captured_stdout()?) else {
+            return Ok(1);
+        };
+
+        // A redirect with no command still creates or truncates its target.
+        let Some(command_name) = command.args.first() else {
+            return Ok(0);
+        };
+
+        if let Some(&builtin) = self.builtins.get(command_name) {
+            let flow = self.run_builtin(builtin, &command.args, &stdio)?;
+            self.exit_requested = matches!(flow, BuiltinFlow::Exit(_));
+            self.return_requested = matches!(flow, BuiltinFlow::Return(_));
+            return Ok(flow.status());
+        }
+
+        if !self.check_command_exists(command_name, stdio.stderr.as_ref())? {
+            return Ok(COMMAND_NOT_FOUND);
+        }
+
+        match self.run_external(&command.args, &stdio) {
+            Ok(status) => Ok(status),
+            Err(err) => {
+                eprintln!("{}", err);
+                Ok(COMMAND_NOT_EXECUTABLE)
+            }
+        }
+    }
+
+    // Commands a builtin runs itself, like the lines of a sourced file, share its stdout.
+    fn run_builtin(
+        &mut self,
+        builtin: BuiltinFn,
+        args: &[String],
+        stdio: &CommandStdio,
+    ) -> io::Result<BuiltinFlow> {
+        let stdout = io::stdout();
+        let stderr = io::stderr();
+        let mut stdout_writer = self
+            .prepare_builtin_output(stdio.stdout.as_ref(), || OutputSink::Stdout(stdout.lock()))?;
+        let mut stderr_writer = self
+            .prepare_builtin_output(stdio.stderr.as_ref(), || OutputSink::Stderr(stderr.lock()))?;
+        let nested_stdout = stdio.stdout.as_ref().map(File::try_clone).transpose()?;
+        let previous = std::mem::replace(&mut self.captured_stdout, nested_stdout);
+        let flow = builtin(self, args, &mut stdout_writer, &mut stderr_writer);
+        self.captured_stdout = previous;
+        let flow = flow?;
+        // Anything still buffered would land after the next external command's output.
+        stdout_writer.flush()?;
+        stderr_writer.flush()?;
+        Ok(flow)
+    }
+
+    fn captured_stdout(&self) -> io::Result<Option<File>> {
+        self.captured_stdout
+            .as_ref()
+            .map(File::try_clone)
+            .transpose()
+    }
+
+    fn open_redirect(&self, command: &Command, stdout: Option<File>) -> Option<CommandStdio> {
+        let mut stdio = CommandStdio {
+            stdout,
+            ..CommandStdio::default()
+        };
+        for spec in &command.redirects {
+            let is_input = matches!(spec.redirect_type, RedirectType::READ);
+            if !matches!((spec.fd, is_input), (0, true) | (1 | 2, false)) {
+                eprintln!("redirect for fd {} is not supported", spec.fd);
+                return None;
+            }
+            let file = match spec.redirect_type {
+                // Duplicate whatever the source fd points at right now, so order matters.
+                RedirectType::DUP(source) => {
+                    let current = match source {
+                        1 => stdio.stdout.as_ref(),
+                        2 => stdio.stderr.as_ref(),
+                        _ => {
+                            eprintln!("{}: Bad file descriptor", source);
+                            return None;
                         }
-                    },
-                    2 => match get_write_output(&spec.target, spec.redirect_type.clone()) {
-                        Ok(file) => (None, Some(file)),
+                    };
+                    let duplicated = match current {
+                        Some(file) => file.try_clone(),
+                        None => duplicate_std_fd(source),
+                    };
+                    match duplicated {
+                        Ok(file) => file,
                         Err(err) => {
-                            eprintln!("failed to open {}: {}", spec.target, err);
-                            continue;
+                            eprintln!("{}: {}", source, err);
+                            return None;
                         }
-                    },
-                    _ => {
-                        eprintln!("redirect for fd {} is not supported", spec.fd);
-                        continue;
+                    }
+                }
+                _ => match get_write_output(&spec.target, spec.redirect_type.clone()) {
+                    Ok(file) => file,
+                    Err(err) if is_input && err.kind() == io::ErrorKind::NotFound => {
+                        eprintln!("{}: No such file or directory", spec.target);
+                        return None;
+                    }
+                    Err(err) => {
+                        eprintln!("failed to open {}: {}", spec.target, err);
+                        return None;
                     }
                 },
-                None => (None, None),
             };
+            match spec.fd {
+                0 => stdio.stdin = Some(file),
+                1 => stdio.stdout = Some(file),
+                _ => stdio.stderr = Some(file),
+            }
+        }
+        Some(stdio)
+    }
 
-            let command_name = parts[0].as_str();
-
-            if let Some(builtin) = self.builtins.get(command_name) {
-                let stdout = io::stdout();
-                let stderr = io::stderr();
-                let mut stdout_writer = self
-                    .prepare_builtin_output(stdout_redirect_file.as_ref(), || {
-                        OutputSink::Stdout(stdout.lock())
-                    })?;
-                let mut stderr_writer = self
-                    .prepare_builtin_output(stderr_redirect_file.as_ref(), || {
-                        OutputSink::Stderr(stderr.lock())
-                    })?;
-                let flow = builtin(
-                    &self.builtins,
-                    &parts,
-                    &mut stdout_writer,
-                    &mut stderr_writer,
-                )?;
-                if let BuiltinFlow::Exit(code) = flow {
-                    process::exit(code);
-                }
-                continue;
+    fn check_command_exists(
+        &self,
+        command_name: &str,
+        stderr_redirect_file: Option<&File>,
+    ) -> io::Result<bool> {
+        let message = if command_name.contains('/') {
+            if Path::new(command_name).exists() {
+                return Ok(true);
             }
+            format!("{}: No such file or directory", command_name)
+        } else {
+            if find_executable(command_name).is_some() {
+                return Ok(true);
+            }
+            format!("{}: command not found", command_name)
+        };
 
-            if find_executable(command_name).is_none() {
-                let stderr = io::stderr();
-                let mut writer = self
-                    .prepare_builtin_output(stderr_redirect_file.as_ref(), || {
-                        OutputSink::Stderr(stderr.lock())
-                    })?;
-                write_line(&mut writer, &format!("{}: command not found", command_name))?;
-                continue;
+        let stderr = io::stderr();
+        let mut writer = self
+            .prepare_builtin_output(stderr_redirect_file, || OutputSink::Stderr(stderr.lock()))?;
+        write_line(&mut writer, &message)?;
+        Ok(false)
+    }
+
+    // The commands run to build the prompt must not change the $? the user sees next.
+    fn prompt(&mut self) -> String {
+        let status = self.last_status;
+        self.run_prompt_command();
+        self.last_status = status;
+        let ps1 = env::var("PS1").unwrap_or_else(|_| DEFAULT_PS1.to_string());
+        let prompt = expand_text(&render_prompt(&ps1, self.jobs.len()), self);
+        self.last_status = status;
+        prompt
+    }
+
+    fn run_prompt_command(&mut self) {
+        if let Ok(prompt_command) = env::var("PROMPT_COMMAND") {
+            match self.execute_line(&prompt_command) {
+                Ok(result) if result.exit_requested => self.exit(),
+                Ok(_) => {}
+                Err(err) => eprintln!("PROMPT_COMMAND: {}", err),
             }
+        }
+    }
 
-            if let Err(err) = self.run_external(
-                &parts,
-                stdout_redirect_file.take(),
-                stderr_redirect_file.take(),
-            ) {
-                eprintln!("{}", err);
+    // The child never returns: it runs body on its own copy of the shell and exits.
+    // Callers read its output on their own thread afterwards: a collector thread started
+    // before the fork could hold a lock the child then waits on forever.
+    fn fork_subshell<F>(&mut self, body: F) -> io::Result<libc::pid_t>
+    where
+        F: FnOnce(&mut Shell) -> io::Result<i32>,
+    {
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => {
+                // Unwinding into the caller's frames would leave a second shell running.
+                let status = match panic::catch_unwind(AssertUnwindSafe(|| body(self))) {
+                    Ok(Ok(status)) => status,
+                    Ok(Err(err)) => {
+                        eprintln!("{}", err);
+                        1
+                    }
+                    Err(_) => 1,
+                };
+                unsafe { libc::_exit(status) }
             }
+            pid => Ok(pid),
         }
     }
 
@@ -129,24 +674,821 @@ impl Shell {
         }
     }
 
-    fn run_external(
+    fn run_external(&self, parts: &[String], stdio: &CommandStdio) -> io::Result<i32> {
+        let mut child = self.spawn_external(parts, stdio, false)?;
+        Ok(exit_code(child.wait()?))
+    }
+
+    fn spawn_external(
         &self,
         parts: &[String],
-        stdout_redirect_file: Option<File>,
-        stderr_redirect_file: Option<File>,
-    ) -> io::Result<()> {
-        let mut command = process::Command::new(&parts[0]);
-        command.args(&parts[1..]);
+        stdio: &CommandStdio,
+        background: bool,
+    ) -> io::Result<Child> {
+        let spawn = |program: &OsStr, args: &[String]| -> io::Result<Child> {
+            let mut command = process::Command::new(program);
+            command.args(args);
+
+            if let Some(file) = &stdio.stdin {
+                command.stdin(Stdio::from(file.try_clone()?));
+            }
+            if let Some(file) = &stdio.stdout {
+                command.stdout(Stdio::from(file.try_clone()?));
+            }
+            if let Some(file) = &stdio.stderr {
+                command.stderr(Stdio::from(file.try_clone()?));
+            }
+            if background {
+                unsafe {
+                    command.pre_exec(|| {
+                        signals::ignore_keyboard_signals();
+                        Ok(())
+                    });
+                }
+            }
+
+            command.spawn()
+        };
+
+        match spawn(OsStr::new(&parts[0]), &parts[1..]) {
+            // Like bash, run executable files without a #! line as shell scripts.
+            Err(err) if err.raw_os_error() == Some(libc::ENOEXEC) => {
+                let script = if parts[0].contains('/') {
+                    PathBuf::from(&parts[0])
+                } else {
+                    find_executable(&parts[0]).ok_or(err)?
+                };
+                let mut args = vec![script.to_string_lossy().into_owned()];
+                args.extend_from_slice(&parts[1..]);
+                spawn(env::current_exe()?.as_os_str(), &args)
+            }
+            result => result,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CommandResult {
+    pub status: i32,
+    pub exit_requested: bool,
+    pub return_requested: bool,
+    pub output: Option<String>,
+}
 
-        if let Some(file) = stdout_redirect_file {
-            command.stdout(Stdio::from(file));
+impl Expand for Shell {
+    fn command_output(&mut self, command: &str) -> String {
+        self.capture(command).output.unwrap_or_default()
+    }
+
+    fn special_parameter(&self, name: char) -> Option<String> {
+        match name {
+            '?' => Some(self.last_status.to_string()),
+            _ => None,
         }
-        if let Some(file) = stderr_redirect_file {
-            command.stderr(Stdio::from(file));
+    }
+}
+
+const HISTORY_SIZE: usize = 1000;
+const COMMAND_NOT_EXECUTABLE: i32 = 126;
+const COMMAND_NOT_FOUND: i32 = 127;
+
+fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
+fn wait_for_pid(pid: libc::pid_t) -> io::Result<i32> {
+    let mut raw_status = 0;
+    while unsafe { libc::waitpid(pid, &mut raw_status, 0) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
         }
+    }
+    Ok(exit_code(ExitStatus::from_raw(raw_status)))
+}
 
-        let mut child = command.spawn()?;
-        let _status = child.wait()?;
-        Ok(())
+fn history_file_path() -> Option<PathBuf> {
+    match env::var("HISTFILE") {
+        Ok(path) => Some(PathBuf::from(path)),
+        Err(_) => env::var("HOME")
+            .ok()
+            .map(|home| Path::new(&home).join(".shell_history")),
+    }
+}
+
+#[derive(Default)]
+struct CommandStdio {
+    stdin: Option<File>,
+    stdout: Option<File>,
+    stderr: Option<File>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{restore_var, TempDir, CWD_LOCK};
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    // Tests that set PROMPT_COMMAND would otherwise run each other's prompt commands.
+    static PROMPT_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn prompt_command_runs_before_each_prompt() {
+        let _guard = PROMPT_LOCK.lock().unwrap();
+        let root = TempDir::new("prompt_command");
+        let log_path = root.join("log");
+        env::set_var(
+            "PROMPT_COMMAND",
+            format!("echo tick >> {}", log_path.display()),
+        );
+
+        let mut shell = Shell::new();
+        shell.run_prompt_command();
+        shell.run_prompt_command();
+        env::remove_var("PROMPT_COMMAND");
+
+        assert_eq!("tick\ntick\n", fs::read_to_string(&log_path).unwrap());
+    }
+
+    #[test]
+    fn prompt_machinery_keeps_the_last_status() {
+        let _guard = PROMPT_LOCK.lock().unwrap();
+        let original_ps1 = env::var_os("PS1");
+        env::set_var("PS1", "[$?$(true)] $(echo sub)> ");
+        env::set_var("PROMPT_COMMAND", "true");
+
+        let mut shell = Shell::new();
+        shell.execute_line("false").unwrap();
+        let prompt = shell.prompt();
+        env::remove_var("PROMPT_COMMAND");
+        restore_var("PS1", original_ps1);
+
+        assert_eq!("[1] sub> ", prompt);
+        assert_eq!(Some("1\n".to_string()), shell.capture("echo $?").output);
+    }
+
+    #[test]
+    fn prompt_escapes_do_not_expand_directory_names() {
+        let _guard = PROMPT_LOCK.lock().unwrap();
+        let _cwd_guard = CWD_LOCK.lock().unwrap();
+        let root = TempDir::new("prompt_dir_name");
+        let dir = root.join("$(touch pwned)`touch ticked`");
+        fs::create_dir_all(&dir).unwrap();
+        let original_dir = env::current_dir().unwrap();
+        let original_ps1 = env::var_os("PS1");
+        env::set_var("PS1", "\\W> ");
+
+        env::set_current_dir(&dir).unwrap();
+        let prompt = Shell::new().prompt();
+        env::set_current_dir(&original_dir).unwrap();
+        restore_var("PS1", original_ps1);
+
+        assert_eq!("$(touch pwned)`touch ticked`> ", prompt);
+        assert!(!dir.join("pwned").exists());
+        assert!(!dir.join("ticked").exists());
+    }
+
+    #[test]
+    fn bare_redirect_creates_target_file() {
+        let root = TempDir::new("bare_redirect");
+        let target = root.join("target.txt");
+        fs::write(&target, "stale contents").unwrap();
+
+        let mut shell = Shell::new();
+        shell.execute_line("false").unwrap();
+        shell
+            .execute_line(&format!("> {}", target.display()))
+            .unwrap();
+
+        assert_eq!("", fs::read_to_string(&target).unwrap());
+        assert_eq!(0, shell.last_status);
+    }
+
+    #[test]
+    fn run_script_executes_each_line() {
+        let root = TempDir::new("run_script");
+        let script = root.join("script.sh");
+        let output = root.join("out.txt");
+        fs::write(
+            &script,
+            format!(
+                "# no shebang line\necho one > {0}\necho \"two\nlines\" >> {0}\n",
+                output.display()
+            ),
+        )
+        .unwrap();
+
+        let mut shell = Shell::new();
+        shell.run_script(script.to_str().unwrap()).unwrap();
+
+        assert_eq!("one\ntwo\nlines\n", fs::read_to_string(&output).unwrap());
+    }
+
+    #[test]
+    fn pipeline_feeds_builtin_output_to_external_commands() {
+        let root = TempDir::new("pipeline");
+        let output = root.join("out.txt");
+
+        let mut shell = Shell::new();
+        shell
+            .execute_line(&format!(
+                "echo hello pipes | tr a-z A-Z | cat > {}",
+                output.display()
+            ))
+            .unwrap();
+
+        assert_eq!("HELLO PIPES\n", fs::read_to_string(&output).unwrap());
+    }
+
+    #[test]
+    fn pipe_with_ampersand_sends_stderr_to_the_next_stage() {
+        let root = TempDir::new("pipe_stderr");
+        let output = root.join("out.txt");
+
+        let mut shell = Shell::new();
+        shell
+            .execute_line(&format!(
+                "sh -c 'echo oops >&2' |& tr a-z A-Z > {0}; type no_such_cmd |& cat >> {0}",
+                output.display()
+            ))
+            .unwrap();
+
+        assert_eq!(
+            "OOPS\nno_such_cmd: not found\n",
+            fs::read_to_string(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn exit_inside_pipeline_does_not_end_the_shell() {
+        let root = TempDir::new("pipeline_exit");
+        let output = root.join("out.txt");
+
+        let mut shell = Shell::new();
+        let result = shell.execute_line("true | exit 5").unwrap();
+        assert_eq!(5, result.status);
+        assert!(!result.exit_requested);
+        assert_eq!(0, shell.execute_line("exit 4 | true").unwrap().status);
+        shell.execute_line("set -o pipefail").unwrap();
+        assert_eq!(5, shell.execute_line("true | exit 5").unwrap().status);
+        let result = shell.execute_line("exit 4 | exit 6 | true").unwrap();
+        assert_eq!(6, result.status);
+        assert!(!result.exit_requested);
+        shell
+            .execute_line(&format!("echo alive > {}", output.display()))
+            .unwrap();
+
+        assert_eq!("alive\n", fs::read_to_string(&output).unwrap());
+    }
+
+    #[test]
+    fn substitutes_command_output() {
+        let root = TempDir::new("substitution");
+        let output = root.join("out.txt");
+
+        let mut shell = Shell::new();
+        shell
+            .execute_line(&format!(
+                "echo [$(echo a   b)] \"[$(printf 'x\\n\\n' | cat)]\" `echo tick` $(echo $(echo nested)) $(exit 3)end > {}",
+                output.display()
+            ))
+            .unwrap();
+
+        assert_eq!(
+            "[a b] [x] tick nested end\n",
+            fs::read_to_string(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn input_redirect_feeds_file_to_stdin() {
+        let root = TempDir::new("input_redirect");
+        fs::write(root.join("in.txt"), "b\na\n").unwrap();
+
+        let mut shell = Shell::new();
+        shell
+            .execute_line(&format!(
+                "sort < {0}/in.txt | cat > {0}/out.txt",
+                root.display()
+            ))
+            .unwrap();
+
+        assert_eq!("a\nb\n", fs::read_to_string(root.join("out.txt")).unwrap());
+    }
+
+    #[test]
+    fn login_shells_read_profile_and_others_read_rc() {
+        let home = TempDir::new("startup");
+        let log = home.join("log.txt");
+        fs::write(
+            home.join(".profile"),
+            format!("echo profile >> {}\n", log.display()),
+        )
+        .unwrap();
+        fs::write(
+            home.join(".shellrc"),
+            format!("echo rc >> {}\n", log.display()),
+        )
+        .unwrap();
+
+        Shell::new().load_startup_files_from(&home, true);
+        assert_eq!("profile\n", fs::read_to_string(&log).unwrap());
+
+        fs::remove_file(&log).unwrap();
+        Shell::new().load_startup_files_from(&home, false);
+        assert_eq!("rc\n", fs::read_to_string(&log).unwrap());
+    }
+
+    #[test]
+    fn routes_multiple_redirects_independently() {
+        let root = TempDir::new("multi_redirect");
+
+        let mut shell = Shell::new();
+        shell
+            .execute_line(&format!(
+                "ls {0}/missing {0} > {0}/first.txt 2> {0}/err.txt > {0}/out.txt",
+                root.display()
+            ))
+            .unwrap();
+
+        assert_eq!("", fs::read_to_string(root.join("first.txt")).unwrap());
+        assert!(fs::read_to_string(root.join("out.txt"))
+            .unwrap()
+            .contains("first.txt"));
+        assert!(fs::read_to_string(root.join("err.txt"))
+            .unwrap()
+            .contains("missing"));
+    }
+
+    #[test]
+    fn duplicates_fds_in_redirect_order() {
+        let root = TempDir::new("dup_redirect");
+
+        let mut shell = Shell::new();
+        for line in [
+            "ls {0}/missing {0} > {0}/both.txt 2>&1",
+            "ls {0}/missing 2>&1 > {0}/late.txt",
+            "ls {0}/missing 2>&1 | cat > {0}/piped.txt",
+        ] {
+            shell
+                .execute_line(&line.replace("{0}", &root.display().to_string()))
+                .unwrap();
+        }
+
+        let both = fs::read_to_string(root.join("both.txt")).unwrap();
+        assert!(both.contains("missing") && both.contains("both.txt"));
+        assert_eq!("", fs::read_to_string(root.join("late.txt")).unwrap());
+        assert!(fs::read_to_string(root.join("piped.txt"))
+            .unwrap()
+            .contains("missing"));
+    }
+
+    #[test]
+    fn history_file_is_loaded_appended_and_capped() {
+        let root = TempDir::new("history");
+        let path = root.join("history");
+        fs::write(&path, "one\ntwo\n").unwrap();
+        let original_size = env::var_os("HISTSIZE");
+        env::set_var("HISTSIZE", "3");
+
+        let mut shell = Shell::new();
+        shell.load_history(path.clone());
+        assert_eq!(shell.history, vec!["one", "two"]);
+        shell.history.push("three".to_string());
+        shell.history.push("four".to_string());
+        shell.save_history().unwrap();
+        let capped = fs::read_to_string(&path).unwrap();
+        env::set_var("HISTSIZE", "0");
+        shell.history.push("five".to_string());
+        shell.save_history().unwrap();
+        restore_var("HISTSIZE", original_size);
+
+        assert_eq!("two\nthree\nfour\n", capped);
+        assert_eq!("", fs::read_to_string(&path).unwrap());
+        let mut shell = Shell::new();
+        shell.load_history(path.clone());
+        assert!(shell.history.is_empty());
+
+        fs::remove_file(&path).unwrap();
+        let mut shell = Shell::new();
+        shell.load_history(path.clone());
+        assert!(shell.history.is_empty());
+    }
+
+    #[test]
+    fn entries_after_history_clear_are_still_saved() {
+        let root = TempDir::new("history_clear");
+        let path = root.join("history");
+        fs::write(&path, "one\ntwo\nthree\n").unwrap();
+
+        let mut shell = Shell::new();
+        shell.load_history(path.clone());
+        shell.execute_line("history -c").unwrap();
+        shell.history.push("new1".to_string());
+        shell.history.push("new2".to_string());
+        shell.save_history().unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+
+        assert!(contents.ends_with("three\nnew1\nnew2\n"));
+    }
+
+    #[test]
+    fn and_or_lists_short_circuit_on_status() {
+        let root = TempDir::new("and_or");
+
+        let mut shell = Shell::new();
+        shell
+            .execute_line(&format!(
+                "ls {0}/missing 2> /dev/null && > {0}/skipped || > {0}/fallback && > {0}/after",
+                root.display()
+            ))
+            .unwrap();
+        assert!(!root.join("skipped").exists());
+        assert!(root.join("fallback").exists());
+        assert!(root.join("after").exists());
+        assert_eq!(0, shell.last_status);
+
+        shell
+            .execute_line("echo ok > /dev/null && no_such_command_here 2> /dev/null")
+            .unwrap();
+        assert_eq!(127, shell.last_status);
+    }
+
+    #[test]
+    fn leading_bang_inverts_the_pipeline_status() {
+        let mut shell = Shell::new();
+        let mut status_of = |line: &str| {
+            shell.execute_line(line).unwrap();
+            shell.last_status
+        };
+
+        assert_eq!(1, status_of("! true"));
+        assert_eq!(0, status_of("! false"));
+        assert_eq!(0, status_of("! ! true"));
+        assert_eq!(1, status_of("! echo a | grep -q a"));
+        assert_eq!(0, status_of("! false && true"));
+        assert_eq!(127, status_of("!true 2> /dev/null"));
+    }
+
+    #[test]
+    fn runs_semicolon_separated_commands_regardless_of_status() {
+        let root = TempDir::new("sequence");
+        let output = root.join("out.txt");
+
+        let mut shell = Shell::new();
+        shell
+            .execute_line(&format!(
+                "echo one > {0}; no_such_command_here 2> /dev/null; echo 'two;three' >> {0}",
+                output.display()
+            ))
+            .unwrap();
+
+        assert_eq!("one\ntwo;three\n", fs::read_to_string(&output).unwrap());
+    }
+
+    #[test]
+    fn expands_the_last_exit_status() {
+        let root = TempDir::new("last_status");
+        let output = root.join("out.txt");
+
+        let mut shell = Shell::new();
+        shell
+            .execute_line(&format!(
+                "ls /no/such/dir 2> /dev/null; echo $? > {0}; cd /no/such/dir 2> /dev/null; echo $? >> {0}; echo $? >> {0}",
+                output.display()
+            ))
+            .unwrap();
+
+        assert_eq!("2\n1\n0\n", fs::read_to_string(&output).unwrap());
+    }
+
+    #[test]
+    fn background_pipelines_return_immediately_and_are_reaped() {
+        let mut shell = Shell::new();
+        let started = Instant::now();
+        shell.execute_line("sleep 0.3 &").unwrap();
+
+        assert!(started.elapsed() < Duration::from_millis(250));
+        assert_eq!(0, shell.last_status);
+        assert!(shell.jobs.reap().is_empty());
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(
+            vec!["[1]+  Done                    sleep 0.3".to_string()],
+            shell.jobs.reap()
+        );
+    }
+
+    #[test]
+    fn background_jobs_survive_a_keyboard_interrupt() {
+        let mut shell = Shell::new();
+        shell.execute_line("sleep 5 &").unwrap();
+        let pid = shell.jobs.pids()[0] as libc::pid_t;
+        // Give the child time to exec with its signal dispositions in place.
+        thread::sleep(Duration::from_millis(100));
+
+        unsafe { libc::kill(pid, libc::SIGINT) };
+        thread::sleep(Duration::from_millis(100));
+        let lines = shell.jobs.list();
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+
+        assert_eq!(
+            vec!["[1]+  Running                 sleep 5 &".to_string()],
+            lines
+        );
+    }
+
+    #[test]
+    fn execute_line_reports_status_and_exit_requests() {
+        let mut shell = Shell::new();
+
+        let result = shell.execute_line("ls /no/such/dir 2> /dev/null").unwrap();
+        assert_eq!(
+            CommandResult {
+                status: 2,
+                exit_requested: false,
+                return_requested: false,
+                output: None,
+            },
+            result
+        );
+
+        let result = shell.execute_line("exit 3; echo unreachable").unwrap();
+        assert!(result.exit_requested);
+        assert_eq!(3, result.status);
+
+        let result = shell.capture("echo captured; exit 4; echo unreachable");
+        assert_eq!(Some("captured\n".to_string()), result.output);
+        assert!(!result.exit_requested);
+        assert_eq!(4, result.status);
+    }
+
+    #[test]
+    fn commands_killed_by_a_signal_report_128_plus_the_signal() {
+        let mut shell = Shell::new();
+
+        shell.execute_line("sh -c 'kill -TERM $$'").unwrap();
+        assert_eq!(143, shell.last_status);
+        shell.execute_line("true | sh -c 'kill -KILL $$'").unwrap();
+        assert_eq!(137, shell.last_status);
+        let result = shell.capture("sh -c 'kill -INT $$'; echo $?");
+        assert_eq!(Some("130\n".to_string()), result.output);
+    }
+
+    #[test]
+    fn sourced_output_follows_redirects_and_pipes() {
+        let root = TempDir::new("source_redirect");
+        fs::write(root.join("lines.sh"), "echo one\nprintf 'two\\n'\n").unwrap();
+        let out = root.join("out.txt");
+        let piped = root.join("piped.txt");
+
+        let mut shell = Shell::new();
+        shell
+            .execute_line(&format!(
+                "source {0}/lines.sh > {1}; . {0}/lines.sh | tr a-z A-Z > {2}",
+                root.display(),
+                out.display(),
+                piped.display()
+            ))
+            .unwrap();
+
+        assert_eq!("one\ntwo\n", fs::read_to_string(&out).unwrap());
+        assert_eq!("ONE\nTWO\n", fs::read_to_string(&piped).unwrap());
+    }
+
+    #[test]
+    fn sourced_exit_ends_the_session_but_return_only_the_script() {
+        let root = TempDir::new("source_return");
+        fs::write(root.join("ret.sh"), "echo before\nreturn 6\necho after\n").unwrap();
+        fs::write(root.join("exit.sh"), "echo leaving\nexit 9\necho after\n").unwrap();
+        let out = root.join("out.txt");
+
+        let mut shell = Shell::new();
+        let result = shell
+            .execute_line(&format!(
+                "source {0}/ret.sh > {1}; echo status $? >> {1}",
+                root.display(),
+                out.display()
+            ))
+            .unwrap();
+        assert!(!result.exit_requested && !result.return_requested);
+        assert_eq!("before\nstatus 6\n", fs::read_to_string(&out).unwrap());
+
+        let result = shell
+            .execute_line(&format!(
+                ". {0}/exit.sh > {1}; echo unreachable >> {1}",
+                root.display(),
+                out.display()
+            ))
+            .unwrap();
+        assert!(result.exit_requested);
+        assert_eq!(9, result.status);
+        assert_eq!("leaving\n", fs::read_to_string(&out).unwrap());
+
+        let result = shell.execute_line("return 3 2> /dev/null").unwrap();
+        assert!(!result.return_requested);
+        assert_eq!(1, result.status);
+    }
+
+    #[test]
+    fn expands_aliases_in_command_position() {
+        let root = TempDir::new("alias");
+        let out = root.join("out.txt");
+        let mut shell = Shell::new();
+        shell
+            .execute_line("alias greet='echo hello' echo='echo loud' loop=loop")
+            .unwrap();
+
+        shell
+            .execute_line(&format!("greet world > {}", out.display()))
+            .unwrap();
+        let greeting = fs::read_to_string(&out).unwrap();
+        shell
+            .execute_line(&format!("echo greet > {}", out.display()))
+            .unwrap();
+        let argument = fs::read_to_string(&out).unwrap();
+        shell
+            .execute_line(&format!("echo x | greet | cat > {}", out.display()))
+            .unwrap();
+        let piped = fs::read_to_string(&out).unwrap();
+        let status = shell.execute_line("loop 2> /dev/null").unwrap().status;
+
+        assert_eq!("loud hello world\n", greeting);
+        assert_eq!("loud greet\n", argument);
+        assert_eq!("loud hello\n", piped);
+        assert_eq!(COMMAND_NOT_FOUND, status);
+    }
+
+    #[test]
+    fn aliases_may_expand_to_command_lists() {
+        let root = TempDir::new("alias_list");
+        let out = root.join("out.txt");
+        let mut shell = Shell::new();
+        shell
+            .execute_line(&format!(
+                "alias multi='echo one > {0}; echo two' chain='true && echo chained'",
+                out.display()
+            ))
+            .unwrap();
+        shell
+            .execute_line("alias skipped='false && echo never || echo fallback'")
+            .unwrap();
+
+        shell
+            .execute_line(&format!(
+                "multi >> {0}; chain >> {0}; skipped >> {0}",
+                out.display()
+            ))
+            .unwrap();
+
+        assert_eq!(
+            "one\ntwo\nchained\nfallback\n",
+            fs::read_to_string(&out).unwrap()
+        );
+        assert_eq!(0, shell.last_status);
+    }
+
+    #[test]
+    fn histignore_patterns_keep_commands_out_of_history() {
+        let mut shell = Shell::new();
+        env::set_var("HISTIGNORE", "ls:pwd:history*");
+        for entry in ["ls", "ls -la", "pwd", "history 5", "echo hi", "  "] {
+            shell.record_history(entry);
+        }
+        env::remove_var("HISTIGNORE");
+
+        let listed = shell.capture("history").output.unwrap();
+        assert_eq!("    1  ls -la\n    2  echo hi\n", listed);
+    }
+
+    #[test]
+    fn ignores_comment_lines_inside_multiline_substitutions() {
+        let root = TempDir::new("substitution_comment");
+        let out = root.join("out.txt");
+        let mut shell = Shell::new();
+        shell
+            .execute_line(&format!(
+                "echo \"$(\n# first (then second)\necho one\necho two # inline\n)\" > {}\n",
+                out.display()
+            ))
+            .unwrap();
+        let output = fs::read_to_string(&out).unwrap();
+
+        assert_eq!("one\ntwo\n", output);
+    }
+
+    #[test]
+    fn scripts_continue_unquoted_substitutions_across_lines() {
+        let root = TempDir::new("substitution_lines");
+        let script = root.join("script.sh");
+        let out = root.join("out.txt");
+        fs::write(
+            &script,
+            format!(
+                "echo $(\n# comment with (\necho inner\necho second # )\n) > {}\n",
+                out.display()
+            ),
+        )
+        .unwrap();
+
+        let mut shell = Shell::new();
+        shell.run_script(script.to_str().unwrap()).unwrap();
+
+        assert_eq!("inner second\n", fs::read_to_string(&out).unwrap());
+        assert_eq!(0, shell.last_status);
+    }
+
+    #[test]
+    fn builtins_and_externals_append_to_one_file_in_order() {
+        let root = TempDir::new("append_order");
+        let out = root.join("out.txt");
+        fs::write(&out, "zero\n").unwrap();
+        let mut shell = Shell::new();
+        let path = out.display();
+        shell
+            .execute_line(&format!(
+                "echo first >> {0} && sh -c 'echo second' >> {0} && echo -n third >> {0} && printf ' fourth\\n' >> {0}",
+                path
+            ))
+            .unwrap();
+        let output = fs::read_to_string(&out).unwrap();
+
+        assert_eq!("zero\nfirst\nsecond\nthird fourth\n", output);
+    }
+
+    #[test]
+    fn builtins_in_pipelines_do_not_change_the_shell() {
+        let mut shell = Shell::new();
+        let _guard = CWD_LOCK.lock().unwrap();
+        let original_dir = env::current_dir().unwrap();
+
+        let status = shell
+            .execute_line("cd / | cat; export PIPELINE_LEAK_TEST=1 | cat")
+            .unwrap()
+            .status;
+        let dir = env::current_dir().unwrap();
+        env::set_current_dir(&original_dir).unwrap();
+
+        assert_eq!(0, status);
+        assert_eq!(original_dir, dir);
+        assert!(env::var("PIPELINE_LEAK_TEST").is_err());
+    }
+
+    #[test]
+    fn builtin_stages_stream_instead_of_running_to_completion() {
+        let root = TempDir::new("pipeline_stream");
+        let script = root.join("endless.sh");
+        let output = root.join("out.txt");
+        fs::write(&script, "echo first\ntimeout 5 yes\n").unwrap();
+
+        let mut shell = Shell::new();
+        let started = Instant::now();
+        shell
+            .execute_line(&format!(
+                "source {} | head -n 1 > {}",
+                script.display(),
+                output.display()
+            ))
+            .unwrap();
+
+        // head exits after one line, and yes then dies of SIGPIPE instead of running on.
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!("first\n", fs::read_to_string(&output).unwrap());
+
+        shell
+            .execute_line(&format!(
+                "echo echo from stdin | source /dev/stdin > {}",
+                output.display()
+            ))
+            .unwrap();
+        assert_eq!("from stdin\n", fs::read_to_string(&output).unwrap());
+    }
+
+    #[test]
+    fn panicking_subshells_exit_with_failure() {
+        let mut shell = Shell::new();
+        let pid = shell
+            .fork_subshell(|_| panic!("subshell panic in test"))
+            .unwrap();
+
+        assert_eq!(1, wait_for_pid(pid).unwrap());
+    }
+
+    #[test]
+    fn substitutions_do_not_change_the_shell() {
+        let mut shell = Shell::new();
+        let _guard = CWD_LOCK.lock().unwrap();
+        let original_dir = env::current_dir().unwrap();
+
+        let inner = shell.capture("cd /; export SUBSTITUTION_LEAK_TEST=yes; pwd");
+        let dir = env::current_dir().unwrap();
+        env::set_current_dir(&original_dir).unwrap();
+
+        assert_eq!(Some("/\n".to_string()), inner.output);
+        assert_eq!(original_dir, dir);
+        assert!(env::var("SUBSTITUTION_LEAK_TEST").is_err());
     }
 }
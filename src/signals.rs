@@ -0,0 +1,89 @@
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static CHILD_EXITED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn record_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn record_child_exit(_signal: libc::c_int) {
+    CHILD_EXITED.store(true, Ordering::SeqCst);
+}
+
+// A handler rather than SIG_IGN: ignored signals stay ignored across exec, so
+// children would no longer die on Ctrl-C.
+pub fn install_interrupt_handler() -> io::Result<()> {
+    // No SA_RESTART, so a blocked prompt read returns EINTR and can be abandoned.
+    install_handler(libc::SIGINT, record_interrupt, 0)
+}
+
+// Unlike SIGINT this must not cut a prompt read short, and stopped children don't count.
+pub fn install_child_handler() -> io::Result<()> {
+    install_handler(
+        libc::SIGCHLD,
+        record_child_exit,
+        libc::SA_RESTART | libc::SA_NOCLDSTOP,
+    )
+}
+
+fn install_handler(
+    signal: libc::c_int,
+    handler: extern "C" fn(libc::c_int),
+    flags: libc::c_int,
+) -> io::Result<()> {
+    let mut action: libc::sigaction = unsafe { mem::zeroed() };
+    action.sa_sigaction = handler as libc::sighandler_t;
+    action.sa_flags = flags;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+    if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Without job control a background command shares the terminal's process group,
+// so POSIX has it ignore the keyboard's SIGINT and SIGQUIT instead.
+// Only calls signal(2), so it is safe between fork and exec.
+pub fn ignore_keyboard_signals() {
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_IGN);
+        libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+    }
+}
+
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+pub fn take_child_exit() -> bool {
+    CHILD_EXITED.swap(false, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sigint_is_recorded_instead_of_killing_the_process() {
+        install_interrupt_handler().unwrap();
+        take_interrupt();
+
+        unsafe { libc::raise(libc::SIGINT) };
+
+        assert!(take_interrupt());
+        assert!(!take_interrupt());
+    }
+
+    #[test]
+    fn child_exits_are_recorded() {
+        install_child_handler().unwrap();
+
+        let status = std::process::Command::new("true").status().unwrap();
+
+        assert!(status.success());
+        assert!(take_child_exit());
+    }
+}
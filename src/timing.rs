@@ -0,0 +1,134 @@
+use std::mem;
+use std::time::Duration;
+
+pub const DEFAULT_TIMEFORMAT: &str = "\nreal\t%3lR\nuser\t%3lU\nsys\t%3lS";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuTimes {
+    pub user: Duration,
+    pub sys: Duration,
+}
+
+impl CpuTimes {
+    // Only waited-for children count, so the shell's own work never shows up in `time`.
+    pub fn now() -> Self {
+        resource_usage(libc::RUSAGE_CHILDREN)
+    }
+
+    pub fn since(self, earlier: CpuTimes) -> CpuTimes {
+        CpuTimes {
+            user: self.user.saturating_sub(earlier.user),
+            sys: self.sys.saturating_sub(earlier.sys),
+        }
+    }
+}
+
+fn resource_usage(who: libc::c_int) -> CpuTimes {
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    if unsafe { libc::getrusage(who, &mut usage) } != 0 {
+        return CpuTimes::default();
+    }
+    CpuTimes {
+        user: timeval_duration(usage.ru_utime),
+        sys: timeval_duration(usage.ru_stime),
+    }
+}
+
+fn timeval_duration(time: libc::timeval) -> Duration {
+    Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+}
+
+pub fn format_report(format: &str, real: Duration, cpu: CpuTimes) -> String {
+    let mut report = String::new();
+    let mut format_chars = format.chars().peekable();
+    while let Some(ch) = format_chars.next() {
+        if ch != '%' {
+            report.push(ch);
+            continue;
+        }
+
+        let mut precision = 3;
+        if let Some(digit) = format_chars.peek().and_then(|c| c.to_digit(10)) {
+            precision = digit.min(3) as usize;
+            format_chars.next();
+        }
+        let long = format_chars.next_if_eq(&'l').is_some();
+        let value = match format_chars.next() {
+            Some('R') => real,
+            Some('U') => cpu.user,
+            Some('S') => cpu.sys,
+            Some('P') => {
+                let percent = if real.is_zero() {
+                    0.0
+                } else {
+                    (cpu.user + cpu.sys).as_secs_f64() * 100.0 / real.as_secs_f64()
+                };
+                report.push_str(&format!("{:.2}", percent));
+                continue;
+            }
+            Some('%') => {
+                report.push('%');
+                continue;
+            }
+            Some(other) => {
+                report.push('%');
+                report.push(other);
+                continue;
+            }
+            None => {
+                report.push('%');
+                break;
+            }
+        };
+        report.push_str(&format_seconds(value, precision, long));
+    }
+    report
+}
+
+fn format_seconds(value: Duration, precision: usize, long: bool) -> String {
+    let seconds = value.as_secs_f64();
+    if long {
+        let minutes = (seconds / 60.0).floor();
+        format!("{}m{:.*}s", minutes, precision, seconds - minutes * 60.0)
+    } else {
+        format!("{:.*}", precision, seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn cpu_bound_child_reports_user_time() {
+        let before = CpuTimes::now();
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg("i=0; while [ $i -lt 300000 ]; do i=$((i+1)); done")
+            .status()
+            .unwrap();
+        let cpu = CpuTimes::now().since(before);
+
+        assert!(status.success());
+        assert!(!cpu.user.is_zero());
+    }
+
+    #[test]
+    fn formats_timeformat_directives() {
+        let cpu = CpuTimes {
+            user: Duration::from_millis(250),
+            sys: Duration::from_millis(250),
+        };
+        let real = Duration::from_millis(61_500);
+
+        assert_eq!(
+            "\nreal\t1m1.500s\nuser\t0m0.250s\nsys\t0m0.250s",
+            format_report(DEFAULT_TIMEFORMAT, real, cpu)
+        );
+        assert_eq!(
+            "61.5 0.25 0.81% 100%",
+            format_report("%1R %2U %P%% 100%%", real, cpu)
+        );
+    }
+}
@@ -1,25 +1,196 @@
 use std::env;
+use std::ffi::{CStr, CString};
 use std::io::{self, Write};
+use std::mem;
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::ptr;
 
 pub fn find_executable(file_path_str: &str) -> Option<PathBuf> {
     let path_var = env::var("PATH").unwrap_or_default();
     for path in path_var.split(':') {
         let file_path_str = format!("{}/{}", path, file_path_str);
         let file_path = PathBuf::from(file_path_str);
-        if let Ok(metadata) = file_path.metadata() {
-            let permissions = metadata.permissions();
-            let is_executable = permissions.mode() & 0o111 != 0;
-            if metadata.is_file() && is_executable {
-                return Some(file_path);
-            }
+        if is_executable_file(&file_path) {
+            return Some(file_path);
         }
     }
     None
 }
 
+pub fn find_all_executables(file_path_str: &str) -> Vec<PathBuf> {
+    let path_var = env::var("PATH").unwrap_or_default();
+    find_all_executables_in(&path_var, file_path_str)
+}
+
+fn find_all_executables_in(path_var: &str, file_path_str: &str) -> Vec<PathBuf> {
+    let mut matches: Vec<PathBuf> = Vec::new();
+    for path in path_var.split(':') {
+        let file_path = PathBuf::from(format!("{}/{}", path, file_path_str));
+        if is_executable_file(&file_path) && !matches.contains(&file_path) {
+            matches.push(file_path);
+        }
+    }
+    matches
+}
+
+pub fn is_executable_file(file_path: &Path) -> bool {
+    match file_path.metadata() {
+        Ok(metadata) => {
+            let is_executable = metadata.permissions().mode() & 0o111 != 0;
+            metadata.is_file() && is_executable
+        }
+        Err(_) => false,
+    }
+}
+
+pub fn expand_tilde(word: &str) -> String {
+    let Some(rest) = word.strip_prefix('~') else {
+        return word.to_string();
+    };
+    let (user, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let home = if user.is_empty() {
+        env::var("HOME").ok()
+    } else {
+        home_dir_of(user)
+    };
+    match home {
+        Some(home) => format!("{}{}", home, path),
+        None => word.to_string(),
+    }
+}
+
+pub fn home_dir_of(user: &str) -> Option<String> {
+    let name = CString::new(user).ok()?;
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut result = ptr::null_mut();
+    let status = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr(passwd.pw_dir) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+pub fn is_valid_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first == '_' || first.is_ascii_alphabetic() => {
+            chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        }
+        _ => false,
+    }
+}
+
 pub fn write_line(writer: &mut dyn Write, content: &str) -> io::Result<()> {
     writer.write_all(content.as_bytes())?;
     writer.write_all(b"\n")
 }
+
+// Tests from any module that change the working directory share this lock.
+#[cfg(test)]
+pub static CWD_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[cfg(test)]
+pub fn restore_var(name: &str, value: Option<std::ffi::OsString>) {
+    match value {
+        Some(value) => env::set_var(name, value),
+        None => env::remove_var(name),
+    }
+}
+
+// A per-process scratch directory that is removed even when an assertion panics.
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn make_executable(dir: &Path, name: &str) -> PathBuf {
+        fs::create_dir_all(dir).unwrap();
+        let file_path = dir.join(name);
+        fs::write(&file_path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&file_path, fs::Permissions::from_mode(0o755)).unwrap();
+        file_path
+    }
+
+    #[test]
+    fn finds_every_match_in_path_order() {
+        let root = TempDir::new("find_all");
+        let first = make_executable(&root.join("first"), "tool");
+        let second = make_executable(&root.join("second"), "tool");
+        let path_var = format!(
+            "{}:{}:{}",
+            root.join("first").display(),
+            root.join("second").display(),
+            root.join("first").display()
+        );
+
+        let matches = find_all_executables_in(&path_var, "tool");
+
+        assert_eq!(vec![first, second], matches);
+    }
+
+    #[test]
+    fn expands_tilde_for_named_users() {
+        let root_home = home_dir_of("root").expect("root should have a passwd entry");
+        assert!(root_home.starts_with('/'));
+
+        assert_eq!(root_home, expand_tilde("~root"));
+        assert_eq!(
+            format!("{}/projects", root_home),
+            expand_tilde("~root/projects")
+        );
+        assert_eq!("~no_such_user_here/x", expand_tilde("~no_such_user_here/x"));
+        assert_eq!("plain~word", expand_tilde("plain~word"));
+    }
+
+    #[test]
+    fn validates_identifiers() {
+        assert!(is_valid_identifier("PATH"));
+        assert!(is_valid_identifier("_private9"));
+        assert!(!is_valid_identifier("1abc"));
+        assert!(!is_valid_identifier("with-dash"));
+        assert!(!is_valid_identifier(""));
+    }
+}
@@ -0,0 +1,77 @@
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::process::{self, Command};
+
+#[test]
+fn version_flag_prints_the_crate_version() {
+    let output = Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"))
+        .arg("--version")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn scripts_exit_with_their_last_status() {
+    let root = env::temp_dir().join(format!("cli_script_{}", process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let inner = root.join("inner");
+    fs::write(&inner, "echo running\nls /no/such/dir 2> /dev/null\n").unwrap();
+    fs::set_permissions(&inner, fs::Permissions::from_mode(0o755)).unwrap();
+    let outer = root.join("outer.sh");
+    fs::write(
+        &outer,
+        format!("{}\necho status=$?\nfalse\n", inner.display()),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"))
+        .arg(&outer)
+        .output()
+        .unwrap();
+    let missing = Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"))
+        .arg(root.join("missing.sh"))
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(
+        "running\nstatus=2\n",
+        String::from_utf8(output.stdout).unwrap()
+    );
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!(Some(127), missing.status.code());
+}
+
+#[test]
+fn scripts_without_a_shebang_run_from_path() {
+    let root = env::temp_dir().join(format!("cli_path_script_{}", process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let script = root.join("noshebang");
+    fs::write(&script, "echo from path\nexit 3\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let outer = root.join("outer.sh");
+    fs::write(&outer, "noshebang arg\necho status=$?\n").unwrap();
+
+    let path = format!(
+        "{}:{}",
+        root.display(),
+        env::var("PATH").unwrap_or_default()
+    );
+    let output = Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"))
+        .arg(&outer)
+        .env("PATH", path)
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(
+        "from path\nstatus=3\n",
+        String::from_utf8(output.stdout).unwrap()
+    );
+    assert!(output.stderr.is_empty());
+}